@@ -0,0 +1,92 @@
+use futures::channel::oneshot;
+use std::cell::RefCell;
+use sycamore::reactive::{create_rc_signal, RcSignal};
+use voxurf::grammar::{parse_choice, parse_yes_no};
+
+use crate::error::Error;
+use crate::feedback::say;
+use crate::settings::Verbosity;
+
+thread_local! {
+    /// The question currently waiting for the user to reply to, if there is one. The
+    /// interface watches this so it can let the user reply.
+    static QUESTION: RcSignal<Option<Question>> = create_rc_signal(None);
+    /// Where the user's reply to the current question should go.
+    static REPLY_TX: RefCell<Option<oneshot::Sender<String>>> = const { RefCell::new(None) };
+}
+
+/// A question for the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    /// The question itself, which is also read out.
+    pub text: String,
+    /// Whether or not this is a yes/no question, in which case the interface can show yes
+    /// and no buttons.
+    pub yes_no: bool,
+}
+
+/// Gets the signal holding the question currently waiting for a reply, if there is one.
+pub fn current_question() -> RcSignal<Option<Question>> {
+    QUESTION.with(|q| q.clone())
+}
+
+/// Replies to the current question, if there is one.
+pub fn reply(answer: String) {
+    if let Some(tx) = REPLY_TX.with(|tx| tx.borrow_mut().take()) {
+        // If nothing's waiting for this anymore, there's nothing to do
+        let _ = tx.send(answer);
+    }
+}
+
+/// Asks the user the given question aloud, waiting for them to reply, either by voice or
+/// through the interface. This returns their reply.
+pub async fn ask(text: &str, yes_no: bool) -> Result<String, Error> {
+    let (tx, rx) = oneshot::channel();
+    REPLY_TX.with(|reply_tx| *reply_tx.borrow_mut() = Some(tx));
+    QUESTION.with(|q| {
+        q.set(Some(Question {
+            text: text.to_string(),
+            yes_no,
+        }))
+    });
+    say(text, Verbosity::Quiet);
+
+    let reply = rx.await;
+    QUESTION.with(|q| q.set(None));
+    // If the sender was dropped, the question was abandoned
+    reply.map_err(|_| Error::Cancelled)
+}
+
+/// Asks the user the given yes/no question, returning whether or not they said yes. If the
+/// user's reply is unclear, they'll be asked again once, and then it will be taken as a no.
+pub async fn confirm(text: &str) -> Result<bool, Error> {
+    let reply = ask(text, true).await?;
+    if let Some(answer) = parse_yes_no(&reply) {
+        return Ok(answer);
+    }
+
+    let reply = ask("Sorry, I didn't catch that. Please say yes or no.", true).await?;
+    Ok(parse_yes_no(&reply).unwrap_or(false))
+}
+
+/// Asks the user the given question about which of the given options they mean, reading the
+/// options out, and returns the index of the one they pick. If the user's reply is unclear,
+/// they'll be asked again once, and then this gives up.
+pub async fn choose(text: &str, options: &[String]) -> Result<usize, Error> {
+    let listed: Vec<_> = options
+        .iter()
+        .enumerate()
+        .map(|(i, option)| format!("{}: {option}.", i + 1))
+        .collect();
+    let reply = ask(&format!("{text} {}", listed.join(" ")), false).await?;
+    if let Some(index) = parse_choice(&reply, options) {
+        return Ok(index);
+    }
+
+    let reply = ask(
+        "Sorry, I didn't catch which one. Please say its number, like \"the first one\".",
+        false,
+    )
+    .await?;
+    parse_choice(&reply, options).ok_or(Error::UnclearChoice)
+}
@@ -0,0 +1,78 @@
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::gate::check_leaving;
+use crate::glue::*;
+use crate::openai::OpenAiApi;
+use crate::router::parse_json_response;
+use crate::undo::push_undo_barrier;
+
+/// Prompt for the LLM when navigating the browser
+static NAVIGATE_PROMPT: &str = include_str!("../prompts/navigate.txt");
+
+/// An action the LLM wants taken on the browser itself.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum BrowserAction {
+    Back,
+    Forward,
+    Reload,
+    NewTab,
+    CloseTab,
+    OpenUrl {
+        url: String,
+        #[serde(default)]
+        new_tab: bool,
+    },
+}
+
+/// Navigates the browser as the user has asked (e.g. going back, opening a website).
+pub async fn navigate(utterance: &str) -> Result<(), Error> {
+    let prompt = NAVIGATE_PROMPT.replace("{{ utterance }}", utterance);
+    let response = OpenAiApi::call(&prompt)
+        .await
+        .map_err(|source| Error::LlmRequest { source })?;
+    let action: BrowserAction = match parse_json_response(&response) {
+        Some(action) => action,
+        None => {
+            log(&response);
+            return Err(Error::InvalidLlmResponse);
+        }
+    };
+    #[cfg(debug_assertions)]
+    log(&format!("{:?}", action));
+
+    let tab_id = current_tab_id().await?;
+    // Anything that takes the user away from this page could lose what they've typed into it
+    let leaves_page = match &action {
+        BrowserAction::NewTab => false,
+        BrowserAction::OpenUrl { new_tab, .. } => !new_tab,
+        _ => true,
+    };
+    if leaves_page {
+        check_leaving(tab_id, "").await?;
+    }
+    // Only actions that leave or reload this page invalidate what could be undone on it
+    let barrier = match &action {
+        BrowserAction::Back => Some(("Went back", "it went to another page")),
+        BrowserAction::Forward => Some(("Went forward", "it went to another page")),
+        BrowserAction::Reload => Some(("Reloaded the page", "it reloaded the page")),
+        BrowserAction::CloseTab => Some(("Closed the tab", "it closed the tab")),
+        BrowserAction::OpenUrl { new_tab: false, .. } => {
+            Some(("Opened a website", "it went to another page"))
+        }
+        BrowserAction::NewTab | BrowserAction::OpenUrl { new_tab: true, .. } => None,
+    };
+    match action {
+        BrowserAction::Back => go_back(tab_id).await,
+        BrowserAction::Forward => go_forward(tab_id).await,
+        BrowserAction::Reload => reload_tab(tab_id).await,
+        BrowserAction::NewTab => new_tab().await,
+        BrowserAction::CloseTab => close_tab(tab_id).await,
+        BrowserAction::OpenUrl { url, new_tab } => open_url(tab_id, &url, new_tab).await,
+    }
+    if let Some((description, reason)) = barrier {
+        push_undo_barrier(description, reason).await;
+    }
+    Ok(())
+}
@@ -1,422 +1,905 @@
-use regex::{Captures, Regex};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use wasm_bindgen::prelude::*;
+use js_sys::Date;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use voxurf::diff::diff;
+use voxurf::locator::Locator;
+use voxurf::redact::redact_text;
+use voxurf::validate::{validate_candidates, validate_expand, validate_script, Violation};
+use voxurf::verify::{unmet_expectations, Expectation};
+use voxurf::{flatten, risk, Node};
+
+use crate::ask::{ask, choose};
+use crate::error::Error;
+use crate::feedback::say;
+use crate::gate::check_risk;
+use crate::glue::*;
+use crate::history::{record, Event, VerificationMethod};
+use crate::macros::{save_macro, MacroStep, MacroTarget};
+use crate::openai::{ChatMessage, OpenAiApi};
+use crate::session::{describe_session, load_session, note_action};
+use crate::settings::{current_settings, Verbosity};
+use crate::settle::wait_until_settled;
+use crate::tree::{redact_tree, take_snapshot, PruningPolicy, Snapshot};
+use crate::undo::{capture_undo_step, push_undo_step};
+use crate::variables::{
+    describe_variables, load_usable_variables, mask_tree_values, mask_variables, store_captures,
+    substitute_variables, unknown_variables,
+};
 
-use crate::openai::OpenAiApi;
-
-/// Maximum number of iterations in tree reconstitution. This prevents infinite loops.
-const MAX_ITERS: usize = 50;
 /// Maximum number of round trips to be made with the LLM.
 const MAX_TRIPS: usize = 5;
+/// Maximum length of the tree given to the LLM, in characters, before only the top of it is
+/// given instead.
+const MAX_TREE_LEN: usize = 60_000;
+/// How deep into the tree to go when the whole thing is too big.
+const SHALLOW_DEPTH: u32 = 12;
+/// Maximum number of times the LLM can try to fix a script that failed, before giving up.
+const MAX_CORRECTIONS: usize = 2;
+/// Maximum number of elements the user is asked to choose between when what they said is
+/// ambiguous.
+const MAX_CANDIDATES: usize = 5;
+/// Maximum number of times the LLM will be asked again after a reply that can't be used,
+/// before giving up.
+const MAX_REPROMPTS: usize = 2;
 
 /// Prompt for the LLM
-static PROMPT: &str = include_str!("../prompt.txt");
+static PROMPT: &str = include_str!("../prompts/act.txt");
+/// Prompt for the LLM on every trip after the first, telling it how the page has changed
+static CONTINUE_PROMPT: &str = include_str!("../prompts/act_continue.txt");
+/// Prompt for the LLM to check whether the user's goal was reached
+static VERIFY_PROMPT: &str = include_str!("../prompts/verify.txt");
+
+/// What the LLM wants to do next.
+enum LlmReply {
+    /// Run the given script, if there is one, and then carry on depending on the status.
+    Act {
+        script: Option<String>,
+        status: Status,
+    },
+    /// See everything under the collapsed node with the given DOM ID.
+    Expand(u32),
+    /// See every node on the page with the given accessible name.
+    Find(String),
+}
 
-fn undefined() -> JsValueSerde {
-    JsValueSerde(JsValue::UNDEFINED)
+/// Where the LLM says the user's task stands, which it gives with every step.
+#[derive(Deserialize, Debug)]
+struct Status {
+    status: TaskStatus,
+    /// What the step's script does, which is read out to the user.
+    #[serde(default)]
+    description: String,
+    /// Why the task is impossible, or has failed.
+    #[serde(default)]
+    reason: Option<String>,
+    /// What the user should be asked, if the LLM needs them to say something.
+    #[serde(default)]
+    question: Option<String>,
+    /// The DOM IDs of the elements the user might have meant, if it's ambiguous.
+    #[serde(default)]
+    candidates: Vec<u32>,
+    /// What should be true of the page once the task is done, to check that it worked.
+    #[serde(default)]
+    expect: Vec<Expectation>,
 }
 
-fn js_value_to_string(val: JsValue) -> String {
-    let wrapped = format!("{:?}", val);
-    let output = wrapped.strip_prefix("JsValue(").unwrap();
-    let output = output.strip_suffix(')').unwrap();
-    let output = output.strip_prefix('"').unwrap_or(output);
-    let output = output.strip_suffix('"').unwrap_or(output);
+/// What the LLM said about whether the user's goal was reached.
+#[derive(Deserialize)]
+struct GoalCheck {
+    reached: bool,
+    #[serde(default)]
+    reason: String,
+}
 
-    output.to_string()
+/// What came of a command that was carried out.
+pub struct Completion {
+    /// The LLM's description of the last action it took.
+    pub description: String,
+    /// Whether or not the page was confirmed to show that the user's goal was reached, or
+    /// `None` if it wasn't checked.
+    pub confirmed: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(transparent)]
-struct JsValueSerde(#[serde(with = "serde_wasm_bindgen::preserve")] JsValue);
+/// The state of the user's task, according to the LLM.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TaskStatus {
+    /// The step's script finishes the task.
+    Done,
+    /// There's more to do after the step's script has run.
+    Continue,
+    /// The user needs to be asked something before the task can carry on.
+    NeedUserInput,
+    /// Several elements could be what the user meant, and they need to choose one.
+    Ambiguous,
+    /// The task can't be done on this page.
+    Impossible,
+    /// Something has gone wrong that the LLM can't fix.
+    Failed,
+}
 
-#[derive(Deserialize)]
-struct AxTree {
-    nodes: Vec<AxNode>,
+/// What a script run by [`run_script`] passed back to us.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ScriptResult {
+    /// Values the script captured from the page with `captureValue(name, value)`, by name.
+    captures: BTreeMap<String, String>,
+    /// Text the script asked to copy to the clipboard with `copyToClipboard(value)`.
+    clipboard: Option<String>,
+    /// What the script returned, if anything.
+    result: Option<String>,
+    /// The exception the script threw, if it failed.
+    exception: Option<String>,
+    timings: ScriptTimings,
+}
+
+/// What happened when a step's script was run. Any variables that were filled in are masked
+/// out of everything here.
+pub struct StepReport {
+    pub timings: ScriptTimings,
+    /// What the script returned, if anything.
+    pub result: Option<String>,
+    /// The exception the script threw, if it failed.
+    pub exception: Option<String>,
+    /// Errors the page logged while the script ran and the page settled afterward.
+    pub console_errors: Vec<String>,
+    /// The names of values the script tried to capture that clash with the user's own
+    /// variables, and so weren't.
+    pub refused_captures: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct AxNode {
-    node_id: String,
-    ignored: bool,
-    role: Option<AxValue>,
-    name: Option<AxValue>,
-    description: Option<AxValue>,
-    value: Option<AxValue>,
-    properties: Option<Vec<AxProperty>>,
-    parent_id: Option<String>,
-    #[serde(rename = "backendDOMNodeId")]
-    backend_dom_node_id: Option<u32>,
+/// How long the parts of running a script took, in milliseconds.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct ScriptTimings {
+    /// Resolving the elements the script referenced.
+    pub resolve_ms: f64,
+    /// Running the script itself.
+    pub run_ms: f64,
+    /// Waiting for the page to settle afterward.
+    #[serde(skip)]
+    pub settle_ms: f64,
+    /// How many elements the script referenced.
+    #[serde(skip)]
+    pub resolved_nodes: usize,
 }
-impl AxNode {
-    fn into_intermediate(self) -> IntermediateNode {
-        let role = self.role.map(|val| val.value.0.as_string()).flatten();
-        IntermediateNode {
-            remove: !self.properties.as_ref().is_some_and(|props| {
-                props
+
+/// Executes the given command against the page's accessibility tree, calling out
+/// to an LLM for processing. If the user wants, the page is checked afterward to make sure
+/// the command worked.
+pub async fn execute_command(command: &str) -> Result<Completion, Error> {
+    // The whole conversation with the LLM, so later trips only need to say what's changed
+    let mut messages = Vec::new();
+    // The last full snapshot of the page the LLM was given, which later ones are compared to
+    let mut last_full: Option<Vec<Node>> = None;
+    let policy = PruningPolicy::default();
+    // Only the names of these are given to the LLM, the values are filled in afterward
+    let mut variables = load_usable_variables().await;
+    let mut steps = Vec::new();
+    let mut snapshot = Snapshot::Full;
+    // Whether or not the whole page was too big to show the last time we tried, in which case
+    // later trips only look around what was acted on
+    let mut large_page = false;
+    // What happened when the last script ran, which the LLM is told along with the page
+    let mut step_feedback = String::new();
+    let mut num_corrections = 0;
+    // If the user had to be asked anything, the steps depend on what they said, so they
+    // can't be replayed as a macro
+    let mut asked_user = false;
+
+    let mut num_trips = 0;
+    while num_trips < MAX_TRIPS {
+        // Attach the debugger to the current tab
+        let tab_id = current_tab_id().await?;
+        attach_debugger(tab_id).await;
+        // After the first trip, we've already waited for the page to settle after acting on
+        // it, but the page might still be loading from before the user said anything
+        if num_trips == 0 {
+            wait_until_settled(tab_id).await;
+        }
+
+        let snapshot_start = Date::now();
+        let mut tree = take_snapshot(tab_id, &snapshot, &policy).await;
+        // If the whole page is too big, start with just the top of it, and the LLM can ask
+        // for more
+        // If what was acted on is gone (e.g. after navigating), there's nothing to look around
+        if matches!(snapshot, Snapshot::Partial(_)) && tree.is_empty() {
+            snapshot = Snapshot::Full;
+            tree = take_snapshot(tab_id, &snapshot, &policy).await;
+        }
+        if snapshot == Snapshot::Full {
+            large_page = tree_to_string(&tree).len() > MAX_TREE_LEN;
+            if large_page {
+                snapshot = Snapshot::Depth(SHALLOW_DEPTH);
+                tree = take_snapshot(tab_id, &snapshot, &policy).await;
+            }
+        }
+        redact_tree(tab_id, &mut tree).await;
+        let snapshot_ms = Date::now() - snapshot_start;
+
+        // Construct the prompt for the LLM; anything the user has already filled in from
+        // their variables should stay secret
+        let tree_str = mask_variables(&tree_to_string(&tree), &variables);
+        let snapshot_note = match snapshot {
+            Snapshot::Full | Snapshot::Roles(_) => String::new(),
+            Snapshot::Depth(_) => {
+                "This page is very large, so only the top of its tree is shown.".to_string()
+            }
+            Snapshot::Subtree(id) => format!(
+                "This is only the part of the page under node {id}, which you asked to expand."
+            ),
+            Snapshot::Partial(id) => format!(
+                "This page is very large, so only what's around node {id}, which you just used, \
+                 is shown."
+            ),
+            Snapshot::Name(ref name) if tree.is_empty() => {
+                format!("Nothing on the page is named \"{name}\".")
+            }
+            Snapshot::Name(ref name) => format!(
+                "These are only the nodes on the page named \"{name}\", which you searched for."
+            ),
+        };
+        let prompt = if messages.is_empty() {
+            PROMPT
+                .replace("{{ snapshot_note }}", &snapshot_note)
+                .replace("{{ tree_json }}", &tree_str)
+                .replace("{{ variables }}", &describe_variables(&variables))
+                .replace("{{ user_command }}", command)
+                .replace(
+                    "{{ session }}",
+                    &mask_variables(&describe_session(&load_session().await), &variables),
+                )
+        } else {
+            // If the LLM has already seen the whole page, it only needs to know what's
+            // changed since
+            let page_update = match (&snapshot, &last_full) {
+                (Snapshot::Full, Some(old)) => describe_changes(old, &tree, &variables),
+                _ if snapshot_note.is_empty() => {
+                    format!("Here's the page now:\n\n```\n{tree_str}\n```")
+                }
+                _ => format!("{snapshot_note}\n\n```\n{tree_str}\n```"),
+            };
+            CONTINUE_PROMPT.replace(
+                "{{ page_update }}",
+                format!("{step_feedback}\n\n{page_update}").trim(),
+            )
+        };
+        step_feedback.clear();
+        if snapshot == Snapshot::Full {
+            last_full = Some(tree.clone());
+        }
+        #[cfg(debug_assertions)]
+        log(&prompt);
+        messages.push(ChatMessage::user(&prompt));
+
+        // Send the prompt to the LLM, extracting its description of the actions it
+        // has taken and the script that wil ltake those actions
+        let llm_start = Date::now();
+        let (script, status) = match get_valid_reply(&mut messages, &tree, &variables).await {
+            Ok(LlmReply::Act { script, status }) => (script, status),
+            // The LLM wants to see more of the page before it does anything
+            Ok(LlmReply::Expand(id)) => {
+                detach_debugger(tab_id).await;
+                snapshot = Snapshot::Subtree(id);
+                num_trips += 1;
+                continue;
+            }
+            Ok(LlmReply::Find(name)) => {
+                detach_debugger(tab_id).await;
+                snapshot = Snapshot::Name(name);
+                num_trips += 1;
+                continue;
+            }
+            Err(err) => {
+                detach_debugger(tab_id).await;
+                return Err(err);
+            }
+        };
+        let llm_ms = Date::now() - llm_start;
+
+        if let Some(script) = &script {
+            // Keep what we're about to do, in case this works and it can be replayed later
+            steps.push(record_step(script, &status.description, &tree));
+
+            let result = run_step(tab_id, script, &status.description, &tree, &mut variables).await;
+            // Detach the debugger immediately so the extension works if the user presses
+            // the button again
+            detach_debugger(tab_id).await;
+            let report = result?;
+            record_timings(snapshot_ms, Some(llm_ms), report.timings);
+            step_feedback = describe_report(&report);
+
+            // If the script failed, show the LLM what went wrong and how the page looks now,
+            // so it can try to fix it, whatever it thought would happen
+            if let Some(error) = report.exception {
+                num_corrections += 1;
+                let retrying = num_corrections <= MAX_CORRECTIONS;
+                record(Event::ScriptFailed {
+                    error,
+                    console_errors: report.console_errors,
+                    retrying,
+                });
+                if !retrying {
+                    return Err(Error::ScriptFailed);
+                }
+                // A step that failed shouldn't be replayed
+                steps.pop();
+                snapshot = Snapshot::Full;
+                num_trips += 1;
+                continue;
+            }
+        } else {
+            detach_debugger(tab_id).await;
+        }
+
+        match status.status {
+            TaskStatus::Done => {
+                let confirmed = if current_settings().verify_goals {
+                    Some(
+                        verify_goal(
+                            &mut messages,
+                            &status.expect,
+                            last_full.as_deref(),
+                            &variables,
+                            &policy,
+                        )
+                        .await?,
+                    )
+                } else {
+                    None
+                };
+                // Only keep what worked, and only if there's something to replay
+                if !asked_user && confirmed != Some(false) && !steps.is_empty() {
+                    save_macro(command, steps).await;
+                }
+                return Ok(Completion {
+                    description: status.description,
+                    confirmed,
+                });
+            }
+            TaskStatus::Continue => {
+                say(&status.description, Verbosity::Verbose);
+                note_action(&status.description);
+            }
+            TaskStatus::NeedUserInput => {
+                let question = status.question.unwrap_or_default();
+                let answer = ask(&question, false).await?;
+                asked_user = true;
+                step_feedback = format!(
+                    "{step_feedback}\n\nYou asked the user \"{question}\", and they said \"{answer}\"."
+                )
+                .trim()
+                .to_string();
+            }
+            TaskStatus::Ambiguous => {
+                let candidates: Vec<_> =
+                    status.candidates.into_iter().take(MAX_CANDIDATES).collect();
+                let options: Vec<_> = candidates
                     .iter()
-                    .any(|prop| prop.name == "focusable" && prop.value.value.0 == JsValue::TRUE)
-            }) || self.ignored
-                || role.as_ref().is_some_and(|r| r == "RootWebArea"),
-            // Only ones that need the default will be later filtered out
-            dom_id: self.backend_dom_node_id.unwrap_or(0),
-            name: self.name.map(|val| val.value.0.as_string()).flatten(),
-            description: self
-                .description
-                .map(|val| val.value.0.as_string())
-                .flatten(),
-            role,
-            value: self.value.map(|val| js_value_to_string(val.value.0)),
-            properties: self
-                .properties
-                .map(|props| {
-                    props
-                        .into_iter()
-                        // All the ones we'll keep are focused, there's no point in preserving
-                        // this
-                        .filter(|prop| prop.name != "focusable")
-                        .map(|prop| (prop.name, js_value_to_string(prop.value.value.0)))
-                        .collect()
+                    .map(|&id| {
+                        Locator::for_node(&tree, id)
+                            .map(|locator| locator.describe())
+                            .unwrap_or_else(|| format!("element {id}"))
+                    })
+                    .collect();
+                let question = status
+                    .question
+                    .filter(|q| !q.trim().is_empty())
+                    .unwrap_or_else(|| "Which one do you mean?".to_string());
+                let index = choose(&question, &options).await?;
+                asked_user = true;
+                step_feedback = format!(
+                    "{step_feedback}\n\nThe user chose node {}, the {}. Carry on with that one.",
+                    candidates[index],
+                    mask_variables(&options[index], &variables)
+                )
+                .trim()
+                .to_string();
+            }
+            TaskStatus::Impossible => {
+                return Err(Error::Impossible {
+                    reason: status.reason.unwrap_or(status.description),
+                })
+            }
+            TaskStatus::Failed => {
+                return Err(Error::TaskFailed {
+                    reason: status.reason.unwrap_or(status.description),
                 })
-                .unwrap_or_default(),
-            id: self.node_id,
-            parent_id: self.parent_id,
-            children: Vec::new(),
+            }
         }
+        // The page has probably changed, so look at all of it again, and tell the LLM what's
+        // different, unless it's too big, in which case look around what was just used
+        snapshot = match script
+            .as_deref()
+            .and_then(|s| referenced_ids(s).last().copied())
+        {
+            Some(id) if large_page => Snapshot::Partial(id),
+            _ => Snapshot::Full,
+        };
+        num_trips += 1;
     }
-}
 
-#[derive(Deserialize, Serialize)]
-struct AxValue {
-    #[serde(rename = "type")]
-    ty: String,
-    #[serde(default = "undefined")]
-    value: JsValueSerde,
+    Err(Error::TooManyTrips { trips: MAX_TRIPS })
 }
 
-#[derive(Deserialize, Serialize)]
-struct AxProperty {
-    name: String,
-    value: AxValue,
+/// Describes how the given tree of the whole page has changed since the given earlier one,
+/// which the LLM has already seen, unless that's most of it (e.g. after navigating
+/// elsewhere), in which case the whole tree is given again.
+fn describe_changes(old: &[Node], tree: &[Node], variables: &BTreeMap<String, String>) -> String {
+    let changes = diff(old, tree);
+    if changes.is_empty() {
+        return "Nothing on the page has changed since your last action.".to_string();
+    }
+    let changes_str = mask_variables(&changes.into_string(), variables);
+    let tree_str = mask_variables(&tree_to_string(tree), variables);
+    if changes_str.len() < tree_str.len() {
+        format!("Since your last action, these elements appeared, changed, or disappeared (everything else on the page is the same as before):\n\n```\n{changes_str}\n```")
+    } else {
+        format!("Here's the page now:\n\n```\n{tree_str}\n```")
+    }
 }
 
-// This has information needed for nested tree reconstitution
-struct IntermediateNode {
-    id: String,
-    parent_id: Option<String>,
-    dom_id: u32,
-    name: Option<String>,
-    description: Option<String>,
-    role: Option<String>,
-    value: Option<String>,
-    properties: HashMap<String, String>,
-    // These are implanted by the parent ID property
-    children: Vec<IntermediateNode>,
-    // Whether or not this node should be removed from the tree structure
-    // as irrelevant
-    remove: bool,
+/// Checks whether or not the user's goal was actually reached, now that the LLM says it's
+/// done, by looking at the page again. If the LLM said what it expected the page to look like
+/// afterward, that's checked directly, otherwise the LLM is shown how the page has changed
+/// since the given snapshot, and asked. The result is recorded in the current run.
+async fn verify_goal(
+    messages: &mut Vec<ChatMessage>,
+    expectations: &[Expectation],
+    last_full: Option<&[Node]>,
+    variables: &BTreeMap<String, String>,
+    policy: &PruningPolicy,
+) -> Result<bool, Error> {
+    let tab_id = current_tab_id().await?;
+    attach_debugger(tab_id).await;
+    let mut tree = take_snapshot(tab_id, &Snapshot::Full, policy).await;
+    redact_tree(tab_id, &mut tree).await;
+    detach_debugger(tab_id).await;
+
+    let (method, confirmed, details) = if !expectations.is_empty() {
+        // The LLM only knows the user's details by their placeholders
+        mask_tree_values(&mut tree, variables);
+        let unmet = unmet_expectations(expectations, &tree);
+        (VerificationMethod::Rules, unmet.is_empty(), unmet)
+    } else {
+        let page_update = match last_full {
+            Some(old) => describe_changes(old, &tree, variables),
+            None => format!(
+                "Here's the page now:\n\n```\n{}\n```",
+                mask_variables(&tree_to_string(&tree), variables)
+            ),
+        };
+        messages.push(ChatMessage::user(
+            &VERIFY_PROMPT.replace("{{ page_update }}", &page_update),
+        ));
+        let response = get_llm_response(messages).await?;
+        // If the LLM can't say, we can't confirm anything
+        let check = Regex::new(r"```json\n([\s\S]+?)\n```")
+            .unwrap()
+            .captures(&response)
+            .and_then(|captures| serde_json::from_str::<GoalCheck>(&captures[1]).ok())
+            .unwrap_or(GoalCheck {
+                reached: false,
+                reason: "The language model couldn't tell".to_string(),
+            });
+        (VerificationMethod::Llm, check.reached, vec![check.reason])
+    };
+    #[cfg(debug_assertions)]
+    log(&format!(
+        "Goal {} by {method:?}: {details:?}",
+        if confirmed {
+            "confirmed"
+        } else {
+            "not confirmed"
+        }
+    ));
+    record(Event::Verification {
+        method,
+        confirmed,
+        details,
+    });
+
+    Ok(confirmed)
 }
-impl IntermediateNode {
-    fn into_final(self) -> Node {
-        Node {
-            dom_id: self.dom_id,
-            name: self.name,
-            description: self.description,
-            role: self.role,
-            value: self.value,
-            properties: self.properties,
-            children: self.children.into_iter().map(|n| n.into_final()).collect(),
+
+/// Runs the given script (written by the LLM, or replayed from a macro) on the page, which
+/// is described by the given tree. This checks the elements the script references are
+/// actually there, makes sure the user is okay with anything risky, and fills in any
+/// variables, keeping anything the script captures in the given variables. The debugger
+/// must already be attached, and this leaves it attached.
+///
+/// A script that throws doesn't make this fail, its exception is in the report instead.
+pub async fn run_step(
+    tab_id: u32,
+    script: &str,
+    description: &str,
+    tree: &[Node],
+    variables: &mut BTreeMap<String, String>,
+) -> Result<StepReport, Error> {
+    // Every element the script references has to be one we told the LLM it could act on
+    let nodes = flatten(tree);
+    let mut targets = Vec::new();
+    for id in referenced_ids(script) {
+        match nodes.iter().find(|n| n.dom_id == id && n.actionable) {
+            Some(node) => targets.push(*node),
+            None => return Err(Error::UnknownElement { id }),
         }
     }
-}
+    let target_ids: Vec<_> = targets.iter().map(|n| n.dom_id).collect();
+    #[cfg(debug_assertions)]
+    log(script);
+
+    // Make sure the user is okay with anything destructive before we do it
+    let risk = risk::assess(script, &targets, tree);
+    check_risk(tab_id, &risk, description).await?;
+    // Whatever the script changes has to be recorded before it changes it
+    let undo_step = capture_undo_step(tab_id, script, description, &targets, tree).await;
+
+    // Only now that we're about to run it do we fill in the real values of any variables
+    let script = substitute_variables(script, variables).await?;
+
+    // This resolves the elements the script references on the page as it runs
+    let settings = current_settings();
+    let result: ScriptResult = serde_wasm_bindgen::from_value(
+        run_script(
+            tab_id,
+            &script,
+            &target_ids,
+            settings.waiting.timeout_ms,
+            settings.typing_delay_ms,
+        )
+        .await,
+    )
+    .unwrap_or_default();
+
+    // Anything the script read from the page is kept for later trips and commands,
+    // without ever going to the LLM
+    let refused_captures = store_captures(result.captures, variables).await;
+    if let Some(text) = result.clipboard {
+        write_clipboard(&text).await;
+    }
 
-#[derive(Serialize)]
-struct Node {
-    dom_id: u32,
-    name: Option<String>,
-    description: Option<String>,
-    role: Option<String>,
-    value: Option<String>,
-    properties: HashMap<String, String>,
-    children: Vec<Node>,
+    // Whatever the script did might take a while to show up, and so might any errors
+    let settle_ms = wait_until_settled(tab_id).await;
+    // Even a script that threw might have changed something first
+    push_undo_step(undo_step).await;
+    let console_errors: Vec<String> =
+        serde_wasm_bindgen::from_value(take_page_errors(tab_id)).unwrap_or_default();
+
+    // Errors can quote whatever the script was working with, including the user's details and
+    // anything that would have been redacted from the page
+    let rules = current_settings().redaction;
+    let clean = |text: &str| redact_text(&mask_variables(text, variables), &rules);
+    Ok(StepReport {
+        timings: ScriptTimings {
+            resolved_nodes: target_ids.len(),
+            settle_ms,
+            ..result.timings
+        },
+        result: result.result.map(|r| clean(&r)),
+        exception: result.exception.map(|e| clean(&e)),
+        console_errors: console_errors.iter().map(|e| clean(e)).collect(),
+        refused_captures,
+    })
 }
-impl Node {
-    /// Converts the node into a string suitable for LLM ingestion. This deliberately elides
-    /// irrelevant information to save on tokens.
-    fn into_string(self, indent_level: usize) -> String {
-        format!(
-            "{tabs}- [{id}] \"{name}\"{role}{desc}{props}{value}{children}",
-            tabs = "\t".repeat(indent_level),
-            id = self.dom_id,
-            name = self.name.unwrap_or("<null>".to_string()),
-            role = if let Some(role) = self.role {
-                format!(" ({role})")
-            } else {
-                String::new()
-            },
-            desc = if let Some(desc) = self.description {
-                format!(" ({desc})")
-            } else {
-                String::new()
-            },
-            props = if !self.properties.is_empty() {
-                let mut s = " {".to_string();
-                for (key, val) in self.properties {
-                    s.push_str(&key);
-                    s.push_str(": ");
-                    s.push_str(&val);
-                    s.push_str(", ")
-                }
-                format!("{}}}", s.strip_suffix(", ").unwrap())
-            } else {
-                String::new()
-            },
-            value = if let Some(val) = self.value {
-                format!(" with value {val}")
-            } else {
-                String::new()
-            },
-            children = if !self.children.is_empty() {
-                let mut children_str = String::new();
-                for child in self.children {
-                    children_str.push('\n');
-                    children_str.push_str(&child.into_string(indent_level + 1));
-                }
-                children_str
-            } else {
-                String::new()
-            }
-        )
+
+/// Describes what happened when a script ran, for the LLM.
+fn describe_report(report: &StepReport) -> String {
+    let mut parts = Vec::new();
+    if let Some(error) = &report.exception {
+        parts.push(format!(
+            "Your last script failed with this error, so it might not have done everything it was meant to:\n\n```text\n{error}\n```\n\nWork out what went wrong from the page as it is now, and write a script that fixes it."
+        ));
+    } else if let Some(result) = &report.result {
+        parts.push(format!(
+            "Your last script returned this:\n\n```text\n{result}\n```"
+        ));
+    }
+    if !report.console_errors.is_empty() {
+        parts.push(format!(
+            "The page logged these errors while your last script ran:\n\n```text\n{}\n```",
+            report.console_errors.join("\n")
+        ));
     }
+    if !report.refused_captures.is_empty() {
+        let names: Vec<_> = report
+            .refused_captures
+            .iter()
+            .map(|name| format!("`{name}`"))
+            .collect();
+        parts.push(format!(
+            "These values weren't captured, because the user already has variables with the same names, so capture them under different names if you need them: {}.",
+            names.join(", ")
+        ));
+    }
+    parts.join("\n\n")
 }
 
-#[wasm_bindgen(module = "/src/glue.js")]
-extern "C" {
-    async fn attach_debugger(tab_id: u32);
-    async fn detach_debugger(tab_id: u32);
-    async fn get_tab_id() -> JsValue;
-    async fn get_raw_ax_tree(tab_id: u32) -> JsValue;
-    async fn execute_js(tab_id: u32, script: &str);
-    async fn dom_enable(tab_id: u32);
-    async fn dom_disable(tab_id: u32);
-    async fn dom_id_to_selector(id: u32, tab_id: u32) -> JsValue;
+/// Records how long the parts of a step took in the history of the current run, given how
+/// long it took to get the tree and ask the LLM (if it was asked).
+pub fn record_timings(snapshot_ms: f64, llm_ms: Option<f64>, timings: ScriptTimings) {
+    #[cfg(debug_assertions)]
+    log(&format!(
+        "Step took {snapshot_ms:.0}ms to snapshot, {:.0}ms in the LLM, {:.0}ms to resolve {} elements, {:.0}ms to run, and {:.0}ms to settle",
+        llm_ms.unwrap_or(0.0),
+        timings.resolve_ms,
+        timings.resolved_nodes,
+        timings.run_ms,
+        timings.settle_ms
+    ));
+    record(Event::Timings {
+        snapshot_ms,
+        llm_ms,
+        resolve_ms: timings.resolve_ms,
+        run_ms: timings.run_ms,
+        settle_ms: timings.settle_ms,
+        resolved_nodes: timings.resolved_nodes,
+    });
 }
-#[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    fn log(v: &str);
+
+/// Gets the regular expression for references to elements by their DOM IDs in scripts
+/// written by the LLM.
+pub fn element_regex() -> Regex {
+    Regex::new(r#"elementFromId\((\d+)\)"#).unwrap()
+}
+
+/// Gets the DOM IDs of all the elements the given script references, without duplicates.
+fn referenced_ids(script: &str) -> Vec<u32> {
+    let mut ids = Vec::new();
+    for caps in element_regex().captures_iter(script) {
+        if let Ok(id) = caps[1].parse() {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+    ids
 }
 
-/// Gets the accessibility tree and filters it, preparing it in a format
-/// digestible by an LLM. This also returns a map of DOM IDs to CSS selectors.
-async fn get_ax_tree(tab_id: u32) -> (Vec<Node>, HashMap<u32, String>) {
-    let tree = get_raw_ax_tree(tab_id).await;
-    let tree: AxTree = serde_wasm_bindgen::from_value(tree).unwrap();
-    // Filter and parse the tree into our own `Node` struct; this will be "flat"
-    // in that each node will have references to its parents and so forth
-    let mut flat_tree: Vec<Option<IntermediateNode>> = tree
-        .nodes
+/// Records the given step so it can be replayed in a macro, noting enough about the
+/// elements it references to find them again later.
+fn record_step(script: &str, description: &str, tree: &[Node]) -> MacroStep {
+    let targets = referenced_ids(script)
         .into_iter()
-        // We'll change these to `None` as we go
-        .map(|raw| Some(raw.into_intermediate()))
+        .filter_map(|dom_id| {
+            Locator::for_node(tree, dom_id).map(|locator| MacroTarget { dom_id, locator })
+        })
         .collect();
+    MacroStep {
+        script: script.to_string(),
+        description: description.to_string(),
+        targets,
+    }
+}
 
-    // The actual tree structure
-    let mut tree = Vec::new();
-    let mut dom_ids = Vec::new();
-    // A map of IDs to locations within `tree` (gradually populated)
-    let mut nodes_ref_map: HashMap<String, Vec<usize>> = HashMap::new();
-    // Keep iterating back through again and again until there's nothing left
-    let mut iters = 0;
-    let mut num_inserted = 0;
-    while flat_tree.iter().any(|n| n.is_some()) && iters < MAX_ITERS {
-        for node_opt in flat_tree.iter_mut() {
-            if let Some(node) = node_opt.take() {
-                if let Some(parent_id) = &node.parent_id {
-                    if let Some(parent_loc_ref) = nodes_ref_map.get_mut(parent_id) {
-                        // Recursively gets the children of the element with the provided location
-                        // vector. This returns the children so we can abstract over returning the
-                        // entire tree if necessary, as in the case of top-level hoisting.
-                        fn get_tree_children(
-                            elems: &mut Vec<Node>,
-                            mut loc: Vec<usize>,
-                        ) -> &mut Vec<Node> {
-                            if loc.is_empty() {
-                                return elems;
-                            }
-
-                            let first_loc = loc.remove(0);
-                            if loc.is_empty() {
-                                &mut elems.get_mut(first_loc).unwrap().children
-                            } else {
-                                get_tree_children(
-                                    &mut elems.get_mut(first_loc).unwrap().children,
-                                    loc,
-                                )
-                            }
+/// Converts the given tree into a string for the LLM.
+fn tree_to_string(tree: &[Node]) -> String {
+    let mut tree_str = String::new();
+    for node in tree.iter().cloned() {
+        tree_str.push_str(&node.into_string(0));
+        tree_str.push('\n');
+    }
+    tree_str.trim().to_string()
+}
+
+/// Asks the LLM for its next move in the given conversation, checking its reply against the
+/// given tree and variables before anything is done with it. If there's anything wrong with
+/// the reply, the LLM is told exactly what, and asked again, until it runs out of attempts.
+async fn get_valid_reply(
+    messages: &mut Vec<ChatMessage>,
+    tree: &[Node],
+    variables: &BTreeMap<String, String>,
+) -> Result<LlmReply, Error> {
+    let mut num_reprompts = 0;
+    loop {
+        let response = get_llm_response(messages).await?;
+        let violations = match parse_reply(&response) {
+            Ok(reply) => {
+                let violations = match &reply {
+                    LlmReply::Act { script, status } => {
+                        let mut violations = Vec::new();
+                        if let Some(script) = script {
+                            violations.extend(validate_script(script, tree));
+                            violations.extend(
+                                unknown_variables(script, variables)
+                                    .into_iter()
+                                    .map(Violation::UnknownVariable),
+                            );
+                        } else if status.status == TaskStatus::Continue {
+                            violations.push(Violation::MissingScript);
                         }
-                        // This is the vector we're inserting our child into
-                        let parent_children = get_tree_children(&mut tree, parent_loc_ref.clone());
-
-                        let id = node.id.clone();
-                        // If this node should be removed, then we'll insert children
-                        // in the same place this was going to be inserted. Otherwise,
-                        // they'll be inserted within this node.
-                        let child_insertion_loc = if node.remove {
-                            parent_loc_ref.clone()
-                        } else {
-                            num_inserted += 1;
-                            dom_ids.push(node.dom_id.clone());
-                            parent_children.push(node.into_final());
-                            let mut self_loc = parent_loc_ref.clone();
-                            self_loc.push(parent_children.len() - 1);
-                            self_loc
+                        // Anything else means the task can't go ahead as it is
+                        let non_acting = match status.status {
+                            TaskStatus::Done | TaskStatus::Continue => None,
+                            TaskStatus::NeedUserInput => Some("need_user_input"),
+                            TaskStatus::Ambiguous => Some("ambiguous"),
+                            TaskStatus::Impossible => Some("impossible"),
+                            TaskStatus::Failed => Some("failed"),
                         };
-                        nodes_ref_map.insert(id, child_insertion_loc);
-                    } else {
-                        // Parent isn't in the tree yet, leave this node behind;
-                        // we'll get it on the next pass
-                        *node_opt = Some(node);
+                        if let (Some(_), Some(status)) = (script, non_acting) {
+                            violations.push(Violation::UnexpectedScript(status));
+                        }
+                        if status.status == TaskStatus::Ambiguous {
+                            violations.extend(validate_candidates(&status.candidates, tree));
+                        }
+                        if status.status == TaskStatus::NeedUserInput
+                            && status.question.as_ref().is_none_or(|q| q.trim().is_empty())
+                        {
+                            violations.push(Violation::MissingQuestion);
+                        }
+                        violations
                     }
-                } else {
-                    let id = node.id.clone();
-                    // If this node should be removed, then we'll insert children at
-                    // the top-level, otherwise within this node in the tree
-                    let child_insertion_loc = if node.remove {
-                        Vec::new()
-                    } else {
-                        num_inserted += 1;
-                        dom_ids.push(node.dom_id.clone());
-                        tree.push(node.into_final());
-                        vec![tree.len() - 1]
-                    };
-                    nodes_ref_map.insert(id, child_insertion_loc);
+                    LlmReply::Expand(id) => validate_expand(*id, tree),
+                    LlmReply::Find(_) => Vec::new(),
+                };
+                if violations.is_empty() {
+                    return Ok(reply);
                 }
+                violations
+            }
+            Err(violation) => {
+                log(&response);
+                vec![violation]
             }
+        };
+
+        let problems: Vec<_> = violations.iter().map(|v| v.to_string()).collect();
+        let retrying = num_reprompts < MAX_REPROMPTS;
+        record(Event::InvalidReply {
+            problems: problems.clone(),
+            retrying,
+        });
+        if !retrying {
+            return Err(match &violations[0] {
+                Violation::UnknownElement(id)
+                | Violation::NotActionable(id)
+                | Violation::NotExpandable(id) => Error::UnknownElement { id: *id },
+                Violation::UnknownVariable(name) => Error::UnknownVariable { name: name.clone() },
+                Violation::ForbiddenOperation(_) | Violation::VariableInString(_) => {
+                    Error::ForbiddenScript
+                }
+                Violation::NoStatus
+                | Violation::InvalidStatus(_)
+                | Violation::MissingScript
+                | Violation::UnexpectedScript(_)
+                | Violation::MissingQuestion
+                | Violation::MissingCandidates
+                | Violation::DynamicElementReference(_)
+                | Violation::InvalidExpand(_) => Error::InvalidLlmResponse,
+            });
         }
-        iters += 1;
-    }
-    if flat_tree.iter().any(|n| n.is_some()) {
-        panic!(
-            "failed to reconstitute nested accessibility tree after {} iterations",
-            MAX_ITERS
-        );
+        num_reprompts += 1;
+        messages.push(ChatMessage::user(&format!(
+            "I can't use your last reply, because:\n\n- {}\n\nPlease reply again in the same format, fixing these problems.",
+            problems.join("\n- ")
+        )));
     }
+}
 
-    // Map the DOM IDs to CSS query selectors; this will work for all our selected nodes
-    // because focusable nodes are guaranteed to exist on the page (apart from the root,
-    // which we've filtered out)
-    dom_enable(tab_id).await;
-    let mut dom_id_map = HashMap::new();
-    for dom_id in dom_ids {
-        let selector = dom_id_to_selector(dom_id, tab_id)
-            .await
-            .as_string()
-            .unwrap();
-        dom_id_map.insert(dom_id, selector);
-    }
-    dom_disable(tab_id).await;
-
-    #[cfg(debug_assertions)]
-    log(&format!(
-        "Total nodes {} reduced to {} relevant nodes",
-        nodes_ref_map.len(),
-        num_inserted
-    ));
-
-    (tree, dom_id_map)
+/// Sends the given conversation to the LLM, adding its response to the conversation and
+/// returning it.
+async fn get_llm_response(messages: &mut Vec<ChatMessage>) -> Result<String, Error> {
+    let response = OpenAiApi::chat(messages)
+        .await
+        .map_err(|source| Error::LlmRequest { source })?;
+    messages.push(ChatMessage::assistant(&response));
+    Ok(response)
 }
 
-/// Executes the given command against the page's accessibility tree, calling out
-/// to an LLM for processing.
-pub async fn execute_command(command: &str) {
-    let mut previous_actions = Vec::new();
+/// Breaks the given response from the LLM into the script that will take its next action (if
+/// there is one) and where it says the task stands, or a request to expand part of the tree or
+/// to find nodes by name.
+fn parse_reply(response: &str) -> Result<LlmReply, Violation> {
+    let script_re = Regex::new(r"```js\n([\s\S]+?)\n```").unwrap();
+    let status_re = Regex::new(r"```json\n([\s\S]+?)\n```").unwrap();
+
+    if let Some(captures) = status_re.captures(response) {
+        let status = serde_json::from_str(&captures[1])
+            .map_err(|err| Violation::InvalidStatus(err.to_string()))?;
+        Ok(LlmReply::Act {
+            script: script_re
+                .captures(response)
+                .map(|captures| captures[1].to_string()),
+            status,
+        })
+    } else if let Some(captures) = Regex::new(r"EXPAND\s+\[?\+?(\d+)")
+        .unwrap()
+        .captures(response)
+    {
+        captures[1]
+            .parse()
+            .map(LlmReply::Expand)
+            .map_err(|_| Violation::InvalidExpand(captures[1].to_string()))
+    } else if let Some(captures) = Regex::new(r#"FIND\s+"([^"\n]+)""#)
+        .unwrap()
+        .captures(response)
+    {
+        Ok(LlmReply::Find(captures[1].trim().to_string()))
+    } else {
+        Err(Violation::NoStatus)
+    }
+}
 
-    let mut num_trips = 0;
-    let mut action_complete = false;
-    while num_trips < MAX_TRIPS {
-        // Attach the debugger to the current tab
-        let tab_id = get_tab_id().await.as_f64().unwrap() as u32;
-        attach_debugger(tab_id).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_status_and_script() {
+        let reply = "Typing the name.\n```js\nawait typeInto(elementFromId(2), \"Ann\");\n```\n\
+                     ```json\n{\"status\": \"continue\", \"description\": \"Typed the name\"}\n```";
+        let Ok(LlmReply::Act { script, status }) = parse_reply(reply) else {
+            panic!("reply wasn't read as an action");
+        };
+        assert_eq!(
+            script.as_deref(),
+            Some("await typeInto(elementFromId(2), \"Ann\");")
+        );
+        assert_eq!(status.status, TaskStatus::Continue);
+        assert_eq!(status.description, "Typed the name");
+    }
 
-        let (tree, dom_id_map) = get_ax_tree(tab_id).await;
+    #[test]
+    fn reads_a_status_without_a_script() {
+        let reply =
+            "```json\n{\"status\": \"need_user_input\", \"question\": \"Which size?\"}\n```";
+        let Ok(LlmReply::Act { script, status }) = parse_reply(reply) else {
+            panic!("reply wasn't read as an action");
+        };
+        assert_eq!(script, None);
+        assert_eq!(status.status, TaskStatus::NeedUserInput);
+        assert_eq!(status.question.as_deref(), Some("Which size?"));
+    }
 
-        // Construct the prompt for the LLM
-        let mut tree_str = String::new();
-        for node in tree {
-            tree_str.push_str(&node.into_string(0));
-            tree_str.push('\n');
-        }
-        let tree_str = tree_str.trim();
-        let prompt = PROMPT
-            .replace("{{ tree_json }}", &tree_str)
-            .replace("{{ user_command }}", command)
-            .replace(
-                "{{ previous_actions }}",
-                &if !previous_actions.is_empty() {
-                    format!("- {}", previous_actions.join("\n- "))
-                } else {
-                    "None".to_string()
-                },
+    #[test]
+    fn reads_expand_requests() {
+        for reply in [
+            "EXPAND 12",
+            "EXPAND [12]",
+            "EXPAND [+12]",
+            "I need more.\nEXPAND +12",
+        ] {
+            assert!(
+                matches!(parse_reply(reply), Ok(LlmReply::Expand(12))),
+                "{reply}"
             );
-        #[cfg(debug_assertions)]
-        log(&prompt);
+        }
+    }
 
-        // Send the prompt to the LLM, extracting its description of the actions it
-        // has taken and the script that wil ltake those actions
-        let (action_description, response_script) = get_llm_response(prompt).await;
+    #[test]
+    fn reads_find_requests() {
+        assert!(matches!(
+            parse_reply("I can't see it.\nFIND \"Place order\""),
+            Ok(LlmReply::Find(name)) if name == "Place order"
+        ));
+    }
 
-        // Resolve DOM node IDs to CSS query selectors in the script
-        let response_script = Regex::new(r#"selectorFromId\((\d+)\)"#)
-            .unwrap()
-            .replace_all(&response_script, |caps: &Captures| {
-                // If this fails, the LLM is referencing a nonexistent node
-                format!("'{}'", dom_id_map.get(&caps[1].parse().unwrap()).unwrap())
-            });
-        #[cfg(debug_assertions)]
-        log(&response_script);
-
-        // This uses the debugger API
-        execute_js(tab_id, &response_script).await;
-        // Detach the debugger immediately so the extension works if the user presses
-        // the button again
-        detach_debugger(tab_id).await;
-
-        // If the LLM thinks it's done, finish, otherwise keep going
-        if action_description.contains("CONTINUE") {
-            // We'll add the action description to our list, and do everything again
-            previous_actions.push(action_description);
-            num_trips += 1;
-        } else {
-            action_complete = true;
-            break;
-        }
+    #[test]
+    fn rejects_expand_requests_that_arent_ids() {
+        assert_eq!(
+            parse_reply("EXPAND 99999999999").err(),
+            Some(Violation::InvalidExpand("99999999999".to_string()))
+        );
     }
 
-    if !action_complete {
-        panic!("failed to complete action in {} trips", MAX_TRIPS);
+    #[test]
+    fn rejects_unreadable_statuses() {
+        assert!(matches!(
+            parse_reply("```json\n{\"status\": \"finished\"}\n```"),
+            Err(Violation::InvalidStatus(_))
+        ));
+        assert!(matches!(
+            parse_reply("```json\n{status: done}\n```"),
+            Err(Violation::InvalidStatus(_))
+        ));
     }
-}
 
-/// Sends the given prompt to the LLM and breaks its response into a description of the
-/// action it has taken to further the user's command and the script that will take that
-/// action.
-async fn get_llm_response(prompt: String) -> (String, String) {
-    let response = OpenAiApi::call(&prompt).await.unwrap();
-    // Define a regular expression for matching JS code fence
-    let re = Regex::new(r"```js\n([\s\S]+?)\n```").unwrap();
-
-    // Attempt to find a match in the response
-    if let Some(captures) = re.captures(&response) {
-        // Extract code and description
-        let code = captures.get(1).unwrap().as_str();
-        let remaining_response = &response[captures.get(0).unwrap().end()..].trim();
-
-        (remaining_response.to_string(), code.to_string())
-    } else {
-        log(&response);
-        panic!("invalid response from llm");
+    #[test]
+    fn rejects_replies_with_nothing_to_do() {
+        assert_eq!(
+            parse_reply("I'm not sure what to do here.").err(),
+            Some(Violation::NoStatus)
+        );
     }
 }
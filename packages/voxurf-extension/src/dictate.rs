@@ -0,0 +1,49 @@
+use serde::Deserialize;
+use voxurf::flatten;
+
+use crate::error::Error;
+use crate::glue::*;
+use crate::openai::OpenAiApi;
+use crate::router::parse_json_response;
+use crate::tree::{redact_tree, take_snapshot, PruningPolicy, Snapshot};
+use crate::undo::{capture_undo_step, push_undo_step};
+
+/// Prompt for the LLM when dictating text
+static DICTATE_PROMPT: &str = include_str!("../prompts/dictate.txt");
+
+#[derive(Deserialize)]
+struct DictateResponse {
+    text: String,
+}
+
+/// Types the text the user has dictated into whatever field they currently have focused.
+pub async fn dictate(utterance: &str) -> Result<(), Error> {
+    let prompt = DICTATE_PROMPT.replace("{{ utterance }}", utterance);
+    let response = OpenAiApi::call(&prompt)
+        .await
+        .map_err(|source| Error::LlmRequest { source })?;
+    let text = match parse_json_response::<DictateResponse>(&response) {
+        Some(res) => res.text,
+        None => {
+            log(&response);
+            return Err(Error::InvalidLlmResponse);
+        }
+    };
+
+    let tab_id = current_tab_id().await?;
+    attach_debugger(tab_id).await;
+    // Remember what the focused field had in it, so this can be undone like anything else
+    let mut tree = take_snapshot(tab_id, &Snapshot::Full, &PruningPolicy::actionable_only()).await;
+    redact_tree(tab_id, &mut tree).await;
+    let focused: Vec<_> = flatten(&tree)
+        .into_iter()
+        .filter(|n| n.properties.get("focused").is_some_and(|v| v == "true"))
+        .take(1)
+        .collect();
+    let undo_step = capture_undo_step(tab_id, "", "Typed what you dictated", &focused, &tree).await;
+    // This goes to the focused element, just like typing would
+    insert_text(tab_id, &text).await;
+    push_undo_step(undo_step).await;
+    detach_debugger(tab_id).await;
+    Ok(())
+}
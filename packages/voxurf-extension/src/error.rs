@@ -0,0 +1,49 @@
+use thiserror::Error;
+
+/// Errors that can occur while handling the user's command. The messages here are read aloud
+/// to the user, so they should be short and make sense to someone who can't see the page.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I couldn't reach the recording server. Is it running?")]
+    RecordingServer {
+        #[source]
+        source: gloo_net::Error,
+    },
+    #[error("Recording failed, please try again.")]
+    RecordingFailed,
+    #[error("I didn't hear anything, please try again.")]
+    EmptyTranscript,
+    #[error("I couldn't find a tab to work in.")]
+    NoTab,
+    #[error("I couldn't reach the language model. Check your internet connection.")]
+    LlmRequest {
+        #[source]
+        source: gloo_net::Error,
+    },
+    #[error("The language model gave me a response I didn't understand.")]
+    InvalidLlmResponse,
+    #[error("The language model tried to do something I don't allow on web pages.")]
+    ForbiddenScript,
+    #[error("The language model tried to use an element that isn't on the page.")]
+    UnknownElement { id: u32 },
+    #[error(
+        "The language model tried to use a variable called {name}, but you haven't saved one."
+    )]
+    UnknownVariable { name: String },
+    #[error("I can't do that: {reason}")]
+    Impossible { reason: String },
+    #[error("That didn't work: {reason}")]
+    TaskFailed { reason: String },
+    #[error("Something kept going wrong on the page, so I stopped.")]
+    ScriptFailed,
+    #[error("I couldn't finish that in {trips} steps, so I stopped.")]
+    TooManyTrips { trips: usize },
+    #[error("I couldn't tell which one you meant, so I stopped.")]
+    UnclearChoice,
+    #[error("There's nothing to undo.")]
+    NothingToUndo,
+    #[error("I can't undo {description}, because {reason}.")]
+    CantUndo { description: String, reason: String },
+    #[error("Okay, I've cancelled that.")]
+    Cancelled,
+}
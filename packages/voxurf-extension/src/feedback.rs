@@ -0,0 +1,11 @@
+use crate::glue::*;
+use crate::settings::{current_settings, Verbosity};
+
+/// Says the given text aloud, if the user's verbosity setting is at least the given level.
+/// Messages that should always be spoken (e.g. errors) should use [`Verbosity::Quiet`].
+pub fn say(text: &str, level: Verbosity) {
+    let settings = current_settings();
+    if settings.verbosity >= level {
+        speak(text, settings.voice, settings.rate);
+    }
+}
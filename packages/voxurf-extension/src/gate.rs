@@ -0,0 +1,69 @@
+use voxurf::risk::{self, Risk, RiskLevel};
+
+use crate::ask::confirm;
+use crate::error::Error;
+use crate::glue::*;
+use crate::settings::{current_settings, site_of};
+use crate::tree::{take_snapshot, PruningPolicy, Snapshot, FIELD_ROLES};
+
+/// Checks whether an action with the given risk can go ahead, asking the user to confirm it
+/// if it's risky. High-risk actions are always confirmed, and low-risk ones are confirmed
+/// unless the user has auto-approved them for the current site. If the user says no, this
+/// will return [`Error::Cancelled`].
+pub async fn check_risk(tab_id: u32, risk: &Risk, action_description: &str) -> Result<(), Error> {
+    let needs_confirmation = match risk.level {
+        RiskLevel::None => false,
+        RiskLevel::Low => {
+            let url = get_tab_url(tab_id).await.as_string().unwrap_or_default();
+            !site_of(&url)
+                .is_some_and(|site| current_settings().auto_approve_low_risk.contains(&site))
+        }
+        RiskLevel::High => true,
+    };
+    if !needs_confirmation {
+        return Ok(());
+    }
+
+    let mut question = String::new();
+    if !action_description.is_empty() {
+        question.push_str(action_description.trim_end_matches('.'));
+        question.push_str(". ");
+    }
+    question.push_str(&format!(
+        "This will {}. Should I go ahead?",
+        risk.reasons.join(", and ")
+    ));
+
+    if confirm(&question).await? {
+        Ok(())
+    } else {
+        Err(Error::Cancelled)
+    }
+}
+
+/// Checks whether it's okay to leave the page in the given tab (e.g. by going back or closing
+/// it), asking the user to confirm it if they'd lose what they've typed into its forms. The
+/// debugger mustn't already be attached.
+pub async fn check_leaving(tab_id: u32, action_description: &str) -> Result<(), Error> {
+    attach_debugger(tab_id).await;
+    // Only fields can have anything typed into them
+    let tree = take_snapshot(
+        tab_id,
+        &Snapshot::Roles(FIELD_ROLES.to_vec()),
+        &PruningPolicy::actionable_only(),
+    )
+    .await;
+    detach_debugger(tab_id).await;
+    check_risk(tab_id, &risk::assess_leaving(&tree), action_description).await
+}
+
+/// Checks whether it's okay to press the given key (e.g. `Enter`) in the given tab, asking the
+/// user to confirm it if it might submit a form or activate something risky. The debugger
+/// mustn't already be attached.
+pub async fn check_key(tab_id: u32, key: &str, action_description: &str) -> Result<(), Error> {
+    attach_debugger(tab_id).await;
+    // Whatever has focus could be anywhere on the page
+    let tree = take_snapshot(tab_id, &Snapshot::Full, &PruningPolicy::actionable_only()).await;
+    detach_debugger(tab_id).await;
+    check_risk(tab_id, &risk::assess_key(key, &tree), action_description).await
+}
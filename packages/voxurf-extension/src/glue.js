@@ -1,28 +1,174 @@
+// What's going on in each tab the debugger is attached to: the network requests in flight
+// (with when they started), and when the last one started or finished, so we can tell when a
+// page has stopped loading things, and any errors the page has logged since we last looked
+const watched = new Map();
+
+// The name of the function scripts call in the page to have us send real input events for
+// them, since those can only be sent through the debugger
+const INPUT_BINDING = "__voxurfInput";
+// The name of the isolated world scripts run in, which is the only place that function exists
+const INPUT_WORLD = "voxurf";
+
+// Kinds of network request that stay open for as long as the page does, and so never finish
+const STREAMING_RESOURCE_TYPES = ["EventSource", "WebSocket"];
+
+// Keys scripts can press by name, with their codes and the text they type, if any
+const NAMED_KEYS = {
+  Enter: ["Enter", 13, "\r"],
+  Tab: ["Tab", 9, null],
+  Escape: ["Escape", 27, null],
+  Backspace: ["Backspace", 8, null],
+  Delete: ["Delete", 46, null],
+  ArrowUp: ["ArrowUp", 38, null],
+  ArrowDown: ["ArrowDown", 40, null],
+  ArrowLeft: ["ArrowLeft", 37, null],
+  ArrowRight: ["ArrowRight", 39, null],
+  Home: ["Home", 36, null],
+  End: ["End", 35, null],
+  PageUp: ["PageUp", 33, null],
+  PageDown: ["PageDown", 34, null],
+  " ": ["Space", 32, " "],
+};
+
+chrome.debugger.onEvent.addListener((source, method, params) => {
+  const state = watched.get(source.tabId);
+  if (!state) return;
+  if (method === "Runtime.consoleAPICalled") {
+    if (params.type === "error") {
+      state.errors.push(params.args.map(arg => arg.value ?? arg.description ?? "").join(" "));
+    }
+    return;
+  } else if (method === "Runtime.exceptionThrown") {
+    const details = params.exceptionDetails;
+    state.errors.push(details?.exception?.description ?? details?.text ?? "Unknown error");
+    return;
+  } else if (method === "Runtime.bindingCalled" && params.name === INPUT_BINDING) {
+    handle_input(source.tabId, params.executionContextId, params.payload);
+    return;
+  } else if (method === "Network.requestWillBeSent") {
+    if (STREAMING_RESOURCE_TYPES.includes(params.type)) return;
+    state.inflight.set(params.requestId, performance.now());
+  } else if (method === "Network.loadingFinished" || method === "Network.loadingFailed") {
+    if (!state.inflight.delete(params.requestId)) return;
+  } else {
+    return;
+  }
+  state.lastActivity = performance.now();
+});
+
 export function attach_debugger(tabId) {
   return new Promise((resolve, reject) => {
-    chrome.debugger.attach({ tabId }, "1.2", () => {
+    chrome.debugger.attach({ tabId }, "1.2", async () => {
+      watched.set(tabId, {
+        inflight: new Map(),
+        lastActivity: performance.now(),
+        errors: [],
+        run: null,
+      });
+      await send_command(tabId, "Network.enable", {});
+      await send_command(tabId, "Runtime.enable", {});
+      // This is only ever in our own isolated worlds, never in the page's, and stays in them
+      // across navigations, until we detach
+      await send_command(tabId, "Runtime.addBinding", {
+        name: INPUT_BINDING,
+        executionContextName: INPUT_WORLD,
+      });
       resolve();
     })
   })
 }
 
 export function detach_debugger(tabId) {
+  watched.delete(tabId);
   return new Promise((resolve, reject) => {
     chrome.debugger.detach({ tabId }, () => resolve());
   });
 }
 
-export function get_raw_ax_tree(tabId) {
+// Waits until there have been no network requests or DOM changes in the given tab for the
+// given time, or until the timeout, whichever comes first. Requests that have been open for
+// longer than the given limit are most likely long polls, which will only finish when there's
+// something new, so they're not waited for
+export async function wait_for_stable(tabId, quietMs, requestLimitMs, timeoutMs) {
+  const start = performance.now();
+  const deadline = start + timeoutMs;
+  let stable = false;
+  while (!stable && performance.now() < deadline) {
+    const remaining = Math.max(0, deadline - performance.now());
+    // DOM changes have to be watched in the page itself; if the page navigates while we're
+    // watching, this won't resolve to anything, and we'll just go around again
+    const res = await send_command(tabId, "Runtime.evaluate", {
+      expression: `new Promise(resolve => {
+        let quiet;
+        const finish = (isQuiet) => {
+          observer.disconnect();
+          clearTimeout(quiet);
+          clearTimeout(limit);
+          resolve(isQuiet);
+        };
+        const observer = new MutationObserver(() => {
+          clearTimeout(quiet);
+          quiet = setTimeout(() => finish(true), ${quietMs});
+        });
+        observer.observe(document, { subtree: true, childList: true, attributes: true, characterData: true });
+        quiet = setTimeout(() => finish(document.readyState === "complete"), ${quietMs});
+        const limit = setTimeout(() => finish(false), ${remaining});
+      })`,
+      awaitPromise: true,
+      returnByValue: true,
+    });
+    const domQuiet = res?.result?.value === true;
+    const state = watched.get(tabId);
+    const now = performance.now();
+    const loading = state
+      ? Array.from(state.inflight.values()).some(started => now - started < requestLimitMs)
+      : false;
+    const networkQuiet = !state || (!loading && now - state.lastActivity >= quietMs);
+    stable = domQuiet && networkQuiet;
+    if (!stable) {
+      await new Promise(resolve => setTimeout(resolve, Math.min(50, remaining)));
+    }
+  }
+  return { waited_ms: performance.now() - start, stable };
+}
+
+export function get_raw_ax_tree(tabId, depth) {
   return new Promise((resolve, reject) => {
     chrome.debugger.sendCommand(
       { tabId },
       "Accessibility.getFullAXTree",
-      {},
+      depth == null ? {} : { depth },
       nodes => resolve(nodes)
     );
   });
 }
 
+// Queries the accessibility tree under the node with the given backend ID (or the whole
+// document, if there isn't one) for nodes with the given role and accessible name (or all of
+// them, if there aren't any)
+export async function query_ax_tree(tabId, backendNodeId, role, name) {
+  if (backendNodeId == null) {
+    const doc = await send_command(tabId, "DOM.getDocument", { depth: 0 });
+    backendNodeId = doc?.root?.backendNodeId;
+  }
+  const res = await send_command(tabId, "Accessibility.queryAXTree", {
+    backendNodeId,
+    role: role ?? undefined,
+    accessibleName: name ?? undefined,
+  });
+  return { nodes: res?.nodes ?? [] };
+}
+
+// Gets the node with the given backend ID from the accessibility tree, along with its
+// ancestors, siblings and children, but nothing else
+export async function get_partial_ax_tree(tabId, backendNodeId) {
+  const res = await send_command(tabId, "Accessibility.getPartialAXTree", {
+    backendNodeId,
+    fetchRelatives: true,
+  });
+  return { nodes: res?.nodes ?? [] };
+}
+
 export function get_tab_id() {
   return new Promise((resolve, reject) => {
     chrome.tabs.query({ active: true, currentWindow: true }, tabs => {
@@ -31,71 +177,382 @@ export function get_tab_id() {
   });
 }
 
-export function dom_enable(tabId) {
+export function execute_js(tabId, script) {
   return new Promise((resolve, reject) => {
     chrome.debugger.sendCommand(
       { tabId },
-      "DOM.enable",
-      {},
+      "Runtime.evaluate",
+      { expression: script, userGesture: true },
       () => resolve()
-    );
+    )
   });
 }
 
-export function dom_disable(tabId) {
+// Sends the given command to the debugger, resolving to its result
+function send_command(tabId, method, params) {
+  return new Promise((resolve, reject) => {
+    chrome.debugger.sendCommand({ tabId }, method, params, (res) => resolve(res));
+  });
+}
+
+// Gets the errors the page in the given tab has logged since the last script was run, or since
+// this was last called
+export function take_page_errors(tabId) {
+  const state = watched.get(tabId);
+  if (!state) return [];
+  const errors = state.errors;
+  state.errors = [];
+  return errors;
+}
+
+// Sends the real input events the given action from a script asks for, then tells the script
+// it's done, or why it failed. Only the script currently being run by `run_script` can ask for
+// these, from the isolated world it runs in and with the token it was given, and each request
+// is only handled once.
+async function handle_input(tabId, contextId, payload) {
+  const run = watched.get(tabId)?.run;
+  let action;
+  try {
+    action = JSON.parse(payload);
+  } catch {
+    return;
+  }
+  if (!run || run.contextId !== contextId || action?.token !== run.token) return;
+  if (typeof action.id !== "string" || run.handled.has(action.id)) return;
+  run.handled.add(action.id);
+
+  let error = null;
+  try {
+    if (action.type === "click") {
+      const { x, y } = await element_centre(tabId, contextId, action.id);
+      for (const type of ["mouseMoved", "mousePressed", "mouseReleased"]) {
+        await send_command(tabId, "Input.dispatchMouseEvent", {
+          type,
+          x,
+          y,
+          button: type === "mouseMoved" ? "none" : "left",
+          clickCount: type === "mouseMoved" ? 0 : 1,
+        });
+      }
+    } else if (action.type === "type") {
+      const text = String(action.text ?? "");
+      // Whatever was in the field has already been selected, so this deletes it
+      if (action.clear) await press_key(tabId, "Backspace", "Backspace", 8, null);
+      if (run.typingDelayMs > 0) {
+        for (const char of text) {
+          if (char === "\n") {
+            await press_key(tabId, "Enter", "Enter", 13, "\r");
+            continue;
+          }
+          await send_command(tabId, "Input.dispatchKeyEvent", {
+            type: "keyDown",
+            key: char,
+            text: char,
+            unmodifiedText: char,
+          });
+          await send_command(tabId, "Input.dispatchKeyEvent", { type: "keyUp", key: char });
+          await new Promise(resolve => setTimeout(resolve, run.typingDelayMs));
+        }
+      } else if (text) {
+        await send_command(tabId, "Input.insertText", { text });
+      }
+    } else if (action.type === "key") {
+      const key = String(action.key ?? "");
+      const [code, keyCode, text] = NAMED_KEYS[key] ?? [
+        /^[a-z]$/i.test(key) ? `Key${key.toUpperCase()}` : "",
+        key.toUpperCase().charCodeAt(0),
+        key.length === 1 ? key : null,
+      ];
+      await press_key(tabId, key, code, keyCode, text);
+    } else {
+      error = `Unknown input action ${action.type}`;
+    }
+  } catch (e) {
+    error = String(e);
+  }
+  await send_command(tabId, "Runtime.evaluate", {
+    expression: `globalThis.__voxurfSettle(${JSON.stringify(action.id)}, ${JSON.stringify(error)})`,
+    contextId,
+  });
+}
+
+// Gets the centre of the element the script asked for the input with the given ID on, from
+// its box model. The element is looked up in the script's isolated world, so the page can't
+// point us at anything else.
+async function element_centre(tabId, contextId, id) {
+  const res = await send_command(tabId, "Runtime.evaluate", {
+    expression: `globalThis.__voxurfTargets.get(${JSON.stringify(id)})`,
+    contextId,
+  });
+  const objectId = res?.result?.objectId;
+  if (!objectId) throw new Error("There's no element to click");
+  const box = await send_command(tabId, "DOM.getBoxModel", { objectId });
+  const quad = box?.model?.content;
+  if (!quad) throw new Error("The element isn't visible");
+  return {
+    x: (quad[0] + quad[2] + quad[4] + quad[6]) / 4,
+    y: (quad[1] + quad[3] + quad[5] + quad[7]) / 4,
+  };
+}
+
+// Runs the given script with some helpers for it to pass values back to us, returning
+// whatever it passed back, along with what the script returned and any exception it threw. The
+// elements with the given backend node IDs are resolved to objects and passed in directly, so
+// the script can get them with `elementFromId`, without us having to touch the page to find
+// them. Typing, key presses, and clicks through the helpers are sent as real input events, with
+// the given delay between each character typed, or all at once if it's zero.
+//
+// The script runs in an isolated world, which shares the page's DOM but not its JavaScript, so
+// the page can't get at the helpers, or ask us for input events itself.
+export async function run_script(tabId, script, backendNodeIds, elementTimeoutMs, typingDelayMs) {
+  const ids = Array.from(backendNodeIds);
+  // Anything logged before now has nothing to do with this script
+  take_page_errors(tabId);
+  const resolveStart = performance.now();
+  const frames = await send_command(tabId, "Page.getFrameTree", {});
+  const world = await send_command(tabId, "Page.createIsolatedWorld", {
+    frameId: frames?.frameTree?.frame?.id,
+    worldName: INPUT_WORLD,
+  });
+  const contextId = world?.executionContextId;
+  const objectIds = await Promise.all(
+    ids.map(backendNodeId =>
+      send_command(tabId, "DOM.resolveNode", { backendNodeId, executionContextId: contextId })
+        .then(res => res?.object?.objectId)
+    )
+  );
+  // We need something in the isolated world to call our function on
+  const global = await send_command(tabId, "Runtime.evaluate", {
+    expression: "globalThis",
+    contextId,
+  });
+  const runStart = performance.now();
+
+  // The only input requests we'll accept are the ones carrying a token only this script has
+  const run = { contextId, typingDelayMs, token: crypto.randomUUID(), handled: new Set() };
+  const state = watched.get(tabId);
+  if (state) state.run = run;
+
+  const functionDeclaration = `async function (...__elements) {
+    const __ids = ${JSON.stringify(ids)};
+    const __token = ${JSON.stringify(run.token)};
+    const elementFromId = (id) => __elements[__ids.indexOf(id)] ?? null;
+    const __voxurf = { captures: {}, clipboard: null };
+    const captureValue = (name, value) => { __voxurf.captures[name] = String(value ?? ""); };
+    const copyToClipboard = (value) => { __voxurf.clipboard = String(value ?? ""); };
+    const waitForElement = (selector, timeoutMs = ${elementTimeoutMs}) => new Promise(resolve => {
+      const found = document.querySelector(selector);
+      if (found) return resolve(found);
+      const observer = new MutationObserver(() => {
+        const found = document.querySelector(selector);
+        if (found) {
+          observer.disconnect();
+          clearTimeout(limit);
+          resolve(found);
+        }
+      });
+      observer.observe(document, { subtree: true, childList: true, attributes: true });
+      const limit = setTimeout(() => { observer.disconnect(); resolve(null); }, timeoutMs);
+    });
+    // Real input events can only be sent by us, so scripts wait for us to send them
+    const __pending = new Map();
+    let __inputCount = 0;
+    globalThis.__voxurfTargets = new Map();
+    globalThis.__voxurfSettle = (id, error) => {
+      const waiting = __pending.get(id);
+      __pending.delete(id);
+      globalThis.__voxurfTargets.delete(id);
+      if (error) waiting?.reject(new Error(error)); else waiting?.resolve();
+    };
+    const __input = (el, action) => {
+      el?.scrollIntoView({ block: "center", inline: "center" });
+      // This only has to be unique within this script, and insecure pages can't make UUIDs
+      const id = String(++__inputCount);
+      if (el) globalThis.__voxurfTargets.set(id, el);
+      return new Promise((resolve, reject) => {
+        __pending.set(id, { resolve, reject });
+        ${INPUT_BINDING}(JSON.stringify({ ...action, id, token: __token }));
+      });
+    };
+    const clickElement = async (el) => {
+      if (!el) throw new Error("There's no element to click");
+      await __input(el, { type: "click" });
+    };
+    const typeInto = async (el, text, replace = true) => {
+      if (!el) throw new Error("There's no element to type into");
+      el.focus();
+      let clear = false;
+      if (replace) {
+        if (el.isContentEditable) {
+          getSelection().selectAllChildren(el);
+          clear = el.textContent !== "";
+        } else if (typeof el.select === "function") {
+          el.select();
+          clear = el.value !== "";
+        }
+      }
+      await __input(el, { type: "type", text: String(text ?? ""), clear });
+    };
+    // Keys go to whatever has focus, just like they would if the user pressed them
+    const pressKey = (key) => __input(null, { type: "key", key: String(key) });
+    const __result = await (async () => {
+      ${script}
+    })();
+    return { ...__voxurf, result: __result === undefined ? null : String(__result) };
+  }`;
+  const res = await send_command(tabId, "Runtime.callFunctionOn", {
+    objectId: global?.result?.objectId,
+    functionDeclaration,
+    arguments: objectIds.map(objectId => (objectId ? { objectId } : { value: null })),
+    userGesture: true,
+    awaitPromise: true,
+    returnByValue: true,
+  });
+  if (state?.run === run) state.run = null;
+  const timings = { resolve_ms: runStart - resolveStart, run_ms: performance.now() - runStart };
+  const details = res?.exceptionDetails;
+  const exception = details ? (details.exception?.description ?? details.text) : null;
+  return { ...(res?.result?.value ?? {}), exception, timings };
+}
+
+export function read_clipboard() {
+  return navigator.clipboard.readText();
+}
+
+export function write_clipboard(text) {
+  return navigator.clipboard.writeText(text);
+}
+
+export function go_back(tabId) {
+  return chrome.tabs.goBack(tabId);
+}
+
+export function go_forward(tabId) {
+  return chrome.tabs.goForward(tabId);
+}
+
+export function reload_tab(tabId) {
+  return chrome.tabs.reload(tabId);
+}
+
+export function close_tab(tabId) {
+  return chrome.tabs.remove(tabId);
+}
+
+export function open_url(tabId, url, newTab) {
+  if (newTab) {
+    return chrome.tabs.create({ url });
+  } else {
+    return chrome.tabs.update(tabId, { url });
+  }
+}
+
+export function new_tab() {
+  return chrome.tabs.create({});
+}
+
+export function insert_text(tabId, text) {
   return new Promise((resolve, reject) => {
     chrome.debugger.sendCommand(
       { tabId },
-      "DOM.disable",
-      {},
+      "Input.insertText",
+      { text },
       () => resolve()
     );
   });
 }
 
-export function dom_id_to_selector(id, tabId) {
+export function press_key(tabId, key, code, keyCode, text) {
+  const params = {
+    key,
+    code,
+    windowsVirtualKeyCode: keyCode,
+    nativeVirtualKeyCode: keyCode,
+  };
   return new Promise((resolve, reject) => {
-    // We need to get the document to force setting `nodeId`s,
-    // see https://issues.chromium.org/issues/41487727
     chrome.debugger.sendCommand(
       { tabId },
-      "DOM.resolveNode",
-      { backendNodeId: id },
-      (res) => {
+      "Input.dispatchKeyEvent",
+      { type: text ? "keyDown" : "rawKeyDown", text, ...params },
+      () => {
         chrome.debugger.sendCommand(
           { tabId },
-          "DOM.getDocument",
-          {},
-          () => {
-            chrome.debugger.sendCommand(
-              { tabId },
-              "DOM.requestNode",
-              // We get data from throughout the tree, so this shoudl traverse everything
-              // (including shadow roots and iframes)
-              { objectId: res.object.objectId },
-              ({ nodeId }) => {
-                chrome.debugger.sendCommand(
-                  { tabId },
-                  "DOM.setAttributeValue",
-                  { nodeId, name: "data-voxurf-id", value: `${nodeId}` },
-                  () => resolve(`[data-voxurf-id="${nodeId}"]`)
-                );
-              }
-            );
-          }
+          "Input.dispatchKeyEvent",
+          { type: "keyUp", ...params },
+          () => resolve()
         );
       }
     );
   });
 }
 
-export function execute_js(tabId, script) {
+export function focus_node(tabId, backendNodeId) {
   return new Promise((resolve, reject) => {
     chrome.debugger.sendCommand(
       { tabId },
-      "Runtime.evaluate",
-      { expression: script, userGesture: true },
-      () => resolve()
-    )
+      "DOM.focus",
+      { backendNodeId },
+      () => {
+        // Things like headings and landmarks can't be focused, so we'll just bring them
+        // into view instead
+        if (chrome.runtime.lastError) {
+          chrome.debugger.sendCommand(
+            { tabId },
+            "DOM.scrollIntoViewIfNeeded",
+            { backendNodeId },
+            () => resolve()
+          );
+        } else {
+          resolve();
+        }
+      }
+    );
   });
 }
+
+export function get_attributes(tabId, backendNodeId) {
+  return new Promise((resolve, reject) => {
+    chrome.debugger.sendCommand(
+      { tabId },
+      "DOM.describeNode",
+      { backendNodeId },
+      (res) => {
+        // These come as a flat list of alternating names and values
+        const attributes = {};
+        const flat = res?.node?.attributes ?? [];
+        for (let i = 0; i + 1 < flat.length; i += 2) {
+          attributes[flat[i]] = flat[i + 1];
+        }
+        resolve(attributes);
+      }
+    );
+  });
+}
+
+export function speak(text, voiceName, rate) {
+  chrome.tts.speak(text, { voiceName: voiceName ?? undefined, rate, enqueue: true });
+}
+
+export function stop_speaking() {
+  chrome.tts.stop();
+}
+
+export function get_voices() {
+  return chrome.tts.getVoices().then(voices => voices.map(voice => voice.voiceName));
+}
+
+export function storage_get(area, key) {
+  return chrome.storage[area].get(key).then(res => res[key]);
+}
+
+export function storage_set(area, key, value) {
+  return chrome.storage[area].set({ [key]: value });
+}
+
+export function sleep(ms) {
+  return new Promise(resolve => setTimeout(resolve, ms));
+}
+
+export function get_tab_url(tabId) {
+  return chrome.tabs.get(tabId).then(tab => tab.url);
+}
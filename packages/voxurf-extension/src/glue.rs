@@ -0,0 +1,68 @@
+//! Bindings to the JavaScript glue code, which wraps the browser extension APIs.
+
+use wasm_bindgen::prelude::*;
+
+use crate::error::Error;
+
+#[wasm_bindgen(module = "/src/glue.js")]
+extern "C" {
+    pub async fn attach_debugger(tab_id: u32);
+    pub async fn detach_debugger(tab_id: u32);
+    pub async fn get_tab_id() -> JsValue;
+    pub async fn get_tab_url(tab_id: u32) -> JsValue;
+    pub async fn get_raw_ax_tree(tab_id: u32, depth: Option<u32>) -> JsValue;
+    pub async fn query_ax_tree(
+        tab_id: u32,
+        backend_node_id: Option<u32>,
+        role: Option<String>,
+        name: Option<String>,
+    ) -> JsValue;
+    pub async fn get_partial_ax_tree(tab_id: u32, backend_node_id: u32) -> JsValue;
+    pub async fn execute_js(tab_id: u32, script: &str);
+    pub fn take_page_errors(tab_id: u32) -> JsValue;
+    pub async fn wait_for_stable(
+        tab_id: u32,
+        quiet_ms: u32,
+        request_limit_ms: u32,
+        timeout_ms: u32,
+    ) -> JsValue;
+    pub async fn run_script(
+        tab_id: u32,
+        script: &str,
+        backend_node_ids: &[u32],
+        element_timeout_ms: u32,
+        typing_delay_ms: u32,
+    ) -> JsValue;
+    pub async fn go_back(tab_id: u32);
+    pub async fn go_forward(tab_id: u32);
+    pub async fn reload_tab(tab_id: u32);
+    pub async fn close_tab(tab_id: u32);
+    pub async fn open_url(tab_id: u32, url: &str, new_tab: bool);
+    pub async fn new_tab();
+    pub async fn insert_text(tab_id: u32, text: &str);
+    pub async fn focus_node(tab_id: u32, backend_node_id: u32);
+    pub async fn get_attributes(tab_id: u32, backend_node_id: u32) -> JsValue;
+    pub async fn press_key(tab_id: u32, key: &str, code: &str, key_code: u32, text: Option<String>);
+    pub fn speak(text: &str, voice: Option<String>, rate: f64);
+    pub fn stop_speaking();
+    pub async fn get_voices() -> JsValue;
+    pub async fn read_clipboard() -> JsValue;
+    pub async fn write_clipboard(text: &str);
+    pub async fn storage_get(area: &str, key: &str) -> JsValue;
+    pub async fn storage_set(area: &str, key: &str, value: JsValue);
+    pub async fn sleep(ms: u32);
+}
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    pub fn log(v: &str);
+}
+
+/// Gets the ID of the tab the user is currently looking at.
+pub async fn current_tab_id() -> Result<u32, Error> {
+    get_tab_id()
+        .await
+        .as_f64()
+        .map(|id| id as u32)
+        .ok_or(Error::NoTab)
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::glue::*;
+use crate::settings::site_of;
+
+/// The key the history is stored under in extension storage. This is kept in local storage,
+/// rather than synced, because it says what the user has been doing.
+const HISTORY_KEY: &str = "history";
+/// The maximum number of runs kept in the history, after which the oldest are dropped.
+const MAX_RUNS: usize = 50;
+
+thread_local! {
+    /// The run currently in progress, if there is one.
+    static CURRENT_RUN: RefCell<Option<Run>> = const { RefCell::new(None) };
+}
+
+/// A record of Voxurf handling one thing the user said, for auditing what it did.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Run {
+    /// What the user said.
+    pub utterance: String,
+    /// The site the user was on when they said it.
+    pub site: Option<String>,
+    /// When the run started, in milliseconds since the Unix epoch.
+    pub started_at: f64,
+    /// Everything notable that happened during the run, in order.
+    pub events: Vec<Event>,
+    /// How the run ended, if it has.
+    pub outcome: Option<RunOutcome>,
+}
+
+/// Something notable that happened during a run.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Something sensitive was redacted from the page before it was sent to the LLM. The
+    /// redacted value itself is never recorded.
+    Redacted {
+        role: Option<String>,
+        name: Option<String>,
+        reason: String,
+    },
+    /// The LLM replied with something that couldn't be used.
+    InvalidReply {
+        /// What was wrong with the reply, as the LLM was told.
+        problems: Vec<String>,
+        /// Whether or not the LLM was asked again.
+        retrying: bool,
+    },
+    /// A script threw an exception.
+    ScriptFailed {
+        error: String,
+        /// Errors the page logged while the script ran.
+        console_errors: Vec<String>,
+        /// Whether or not the LLM was asked to fix it.
+        retrying: bool,
+    },
+    /// The page was checked to see whether the user's goal was reached.
+    Verification {
+        method: VerificationMethod,
+        /// Whether or not it was.
+        confirmed: bool,
+        /// Why it was or wasn't, or which expectations weren't met.
+        details: Vec<String>,
+    },
+    /// How long each part of a step took, in milliseconds.
+    Timings {
+        /// Getting the accessibility tree and redacting it.
+        snapshot_ms: f64,
+        /// Waiting for the page to settle after the script ran.
+        #[serde(default)]
+        settle_ms: f64,
+        /// Waiting for the LLM, if it was asked (it isn't for macros).
+        llm_ms: Option<f64>,
+        /// Resolving the elements the script referenced.
+        resolve_ms: f64,
+        /// Running the script.
+        run_ms: f64,
+        /// How many elements the script referenced.
+        resolved_nodes: usize,
+    },
+}
+
+/// How the page was checked to see whether the user's goal was reached.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationMethod {
+    /// Against what the LLM said the page should look like.
+    Rules,
+    /// By asking the LLM.
+    Llm,
+}
+
+/// How a run ended.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunOutcome {
+    /// The user's question was answered.
+    Answered,
+    /// What the user asked for was done.
+    Done { description: Option<String> },
+    /// What the user asked for was done, but the page was checked afterward, and it couldn't be
+    /// confirmed that it worked.
+    Unconfirmed { description: Option<String> },
+    /// Something went wrong.
+    Failed { error: String },
+}
+
+/// Starts recording a new run for the given utterance. Any run that was never finished is
+/// discarded.
+pub async fn start_run(utterance: &str) {
+    let site = match current_tab_id().await {
+        Ok(tab_id) => site_of(&get_tab_url(tab_id).await.as_string().unwrap_or_default()),
+        Err(_) => None,
+    };
+    let run = Run {
+        utterance: utterance.to_string(),
+        site,
+        started_at: js_sys::Date::now(),
+        events: Vec::new(),
+        outcome: None,
+    };
+    CURRENT_RUN.with(|r| *r.borrow_mut() = Some(run));
+}
+
+/// Records the given event in the current run. If there isn't one, this does nothing.
+pub fn record(event: Event) {
+    CURRENT_RUN.with(|r| {
+        if let Some(run) = r.borrow_mut().as_mut() {
+            run.events.push(event);
+        }
+    });
+}
+
+/// Finishes the current run with the given outcome, saving it to the history.
+pub async fn finish_run(outcome: RunOutcome) {
+    let Some(mut run) = CURRENT_RUN.with(|r| r.borrow_mut().take()) else {
+        return;
+    };
+    run.outcome = Some(outcome);
+
+    let mut history = load_history().await;
+    history.push(run);
+    if history.len() > MAX_RUNS {
+        history.drain(..history.len() - MAX_RUNS);
+    }
+    let value = serde_wasm_bindgen::to_value(&history).unwrap();
+    storage_set("local", HISTORY_KEY, value).await;
+}
+
+/// Loads the history of runs from extension storage, oldest first.
+pub async fn load_history() -> Vec<Run> {
+    let stored = storage_get("local", HISTORY_KEY).await;
+    serde_wasm_bindgen::from_value(stored).unwrap_or_default()
+}
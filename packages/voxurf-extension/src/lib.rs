@@ -1,19 +1,50 @@
+mod ask;
+mod browser;
 mod command;
+mod dictate;
+mod error;
+mod feedback;
+mod gate;
+mod glue;
+mod history;
+mod local;
+mod macros;
 mod openai;
+mod read;
+mod router;
+mod screen_reader;
+mod session;
+mod settings;
+mod settle;
+mod tree;
+mod undo;
+mod variables;
 
 use gloo_net::http::Request;
 use sycamore::prelude::*;
 use wasm_bindgen::prelude::*;
 
-use crate::command::execute_command;
+use crate::ask::{current_question, reply};
+use crate::error::Error;
+use crate::feedback::say;
+use crate::history::{finish_run, start_run, RunOutcome};
+use crate::macros::MacrosPanel;
+use crate::router::{handle_utterance, Outcome};
+use crate::session::{finish_turn, note_action, start_turn};
+use crate::settings::{load_settings, SettingsPanel, Verbosity};
+use crate::variables::VariablesPanel;
 
 #[wasm_bindgen]
 pub fn main() {
     #[cfg(debug_assertions)]
     console_error_panic_hook::set_once();
 
-    sycamore::render(|cx| {
-        view! { cx, App() }
+    // We need the settings before anything can be said
+    wasm_bindgen_futures::spawn_local(async {
+        load_settings().await;
+        sycamore::render(|cx| {
+            view! { cx, App() }
+        });
     });
 }
 
@@ -22,11 +53,17 @@ enum AppState {
     Idle,
     Recording,
     Executing,
+    /// Recording the user's reply to a question asked while executing.
+    RecordingReply,
 }
 
 #[component]
 fn App<G: Html>(cx: Scope) -> View<G> {
     let state = create_signal(cx, AppState::Idle);
+    // Any text we should show the user, like the answer to a question
+    let output = create_signal(cx, String::new());
+    // Any question we're waiting for the user to reply to
+    let question = create_ref(cx, current_question());
 
     view! { cx,
         div(class="right") {
@@ -35,59 +72,185 @@ fn App<G: Html>(cx: Scope) -> View<G> {
                     "rounded-full h-24 w-24 p-2 {}",
                     match *state.get() {
                         AppState::Idle => "bg-red-500",
-                        AppState::Recording => "bg-blue-500",
+                        AppState::Recording | AppState::RecordingReply => "bg-blue-500",
                         AppState::Executing => "bg-emerald-500",
                     }
                 ),
-                disabled = *state.get() == AppState::Executing,
+                // The user can only record while executing if we've asked them something
+                disabled = *state.get() == AppState::Executing && question.get().is_none(),
                 on:click = move |_| {
                     sycamore::futures::spawn_local_scoped(cx, async move {
                         match *state.get() {
                             AppState::Idle => {
                                 // Set the state *after* we're ready to record to avoid
                                 // speaking before recording
-                                start_recording().await;
-                                state.set(AppState::Recording);
+                                match start_recording().await {
+                                    Ok(()) => state.set(AppState::Recording),
+                                    Err(err) => report_error(output, err),
+                                }
                             },
                             AppState::Recording => {
                                 // Set the state *before* we start working so we encapsulate
                                 // everything in the execution phase
                                 state.set(AppState::Executing);
-                                let command = stop_recording().await;
-                                execute_command(&command).await;
+                                match run_command().await {
+                                    Ok(Outcome::Answer(answer)) => {
+                                        say(&answer, Verbosity::Quiet);
+                                        output.set(answer);
+                                    },
+                                    Ok(Outcome::Done(description)) => {
+                                        if let Some(description) = &description {
+                                            say(description, Verbosity::Normal);
+                                        }
+                                        say("Done.", Verbosity::Normal);
+                                        output.set(description.unwrap_or_else(|| "Done.".to_string()));
+                                    },
+                                    Ok(Outcome::Unconfirmed(description)) => {
+                                        if let Some(description) = &description {
+                                            say(description, Verbosity::Normal);
+                                        }
+                                        // This is as important as an error
+                                        say("I could not confirm that worked.", Verbosity::Quiet);
+                                        output.set(format!(
+                                            "{} I could not confirm that worked.",
+                                            description.unwrap_or_default()
+                                        ).trim().to_string());
+                                    },
+                                    Err(err) => report_error(output, err),
+                                }
+                                state.set(AppState::Idle);
+                            },
+                            AppState::Executing => {
+                                match start_recording().await {
+                                    Ok(()) => state.set(AppState::RecordingReply),
+                                    Err(err) => report_error(output, err),
+                                }
+                            },
+                            AppState::RecordingReply => {
+                                state.set(AppState::Executing);
+                                match stop_recording().await {
+                                    Ok(answer) => reply(answer),
+                                    // The question is still waiting, so they can try again
+                                    Err(err) => report_error(output, err),
+                                }
                             },
-                            AppState::Executing => unreachable!(),
                         }
                     });
                 }
             ) {
                 img(src = "assets/logo_core.webp") {}
             }
+            p(class="mt-2", aria-live="polite") { (output.get()) }
+            (if let Some(q) = &*question.get() {
+                let text = q.text.clone();
+                let yes_no = q.yes_no;
+                view! { cx,
+                    div(class="mt-2") {
+                        p(class="font-bold") { (text) }
+                        (if yes_no {
+                            view! { cx,
+                                div(class="flex gap-2") {
+                                    button(
+                                        class="rounded bg-emerald-500 px-3 py-1",
+                                        on:click = |_| reply("yes".to_string())
+                                    ) { "Yes" }
+                                    button(
+                                        class="rounded bg-red-500 px-3 py-1",
+                                        on:click = |_| reply("no".to_string())
+                                    ) { "No" }
+                                }
+                            }
+                        } else {
+                            view! { cx, }
+                        })
+                    }
+                }
+            } else {
+                view! { cx, }
+            })
+            SettingsPanel()
+            VariablesPanel()
+            MacrosPanel()
         }
     }
 }
 
+/// Tells the user about the given error, both aloud and on the screen.
+fn report_error(output: &Signal<String>, err: Error) {
+    let message = err.to_string();
+    say(&message, Verbosity::Quiet);
+    output.set(message);
+}
+
+/// Stops the recording and handles whatever the user said.
+async fn run_command() -> Result<Outcome, Error> {
+    let command = stop_recording().await?;
+    say(&format!("I heard: {command}"), Verbosity::Normal);
+
+    start_run(&command).await;
+    start_turn(&command).await;
+    let outcome = handle_utterance(&command).await;
+    // Commands that didn't go through the LLM won't have noted what they did
+    if let Ok(Outcome::Done(Some(description)) | Outcome::Unconfirmed(Some(description))) = &outcome
+    {
+        note_action(description);
+    }
+    finish_turn(&match &outcome {
+        Ok(Outcome::Answer(_)) => "answered".to_string(),
+        Ok(Outcome::Done(_)) => "done".to_string(),
+        Ok(Outcome::Unconfirmed(_)) => {
+            "done, but it couldn't be confirmed that it worked".to_string()
+        }
+        Err(err) => format!("failed with \"{err}\""),
+    })
+    .await;
+    finish_run(match &outcome {
+        Ok(Outcome::Answer(_)) => RunOutcome::Answered,
+        Ok(Outcome::Done(description)) => RunOutcome::Done {
+            description: description.clone(),
+        },
+        Ok(Outcome::Unconfirmed(description)) => RunOutcome::Unconfirmed {
+            description: description.clone(),
+        },
+        Err(err) => RunOutcome::Failed {
+            error: err.to_string(),
+        },
+    })
+    .await;
+    outcome
+}
+
 /// Begins the recording on the local server. This will return when recording has
 /// started.
-async fn start_recording() {
+async fn start_recording() -> Result<(), Error> {
     let resp = Request::get("http://localhost:3000/start-recording")
         .send()
         .await
-        .unwrap();
-    assert_eq!(resp.status(), 200);
+        .map_err(|source| Error::RecordingServer { source })?;
+    if resp.status() != 200 {
+        return Err(Error::RecordingFailed);
+    }
+    Ok(())
 }
 
 /// Ends the recording on the local server, returning the transcribed text.
-async fn stop_recording() -> String {
+async fn stop_recording() -> Result<String, Error> {
     let resp = Request::get("http://localhost:3000/end-recording")
         .send()
         .await
-        .unwrap();
-    assert_eq!(resp.status(), 200);
-    resp
+        .map_err(|source| Error::RecordingServer { source })?;
+    if resp.status() != 200 {
+        return Err(Error::RecordingFailed);
+    }
+    let transcript = resp
         .text()
         .await
-        .unwrap()
+        .map_err(|source| Error::RecordingServer { source })?;
+    let transcript = transcript.trim();
+    if transcript.is_empty() {
+        return Err(Error::EmptyTranscript);
+    }
+    Ok(transcript.to_string())
 }
 
 // #[component]
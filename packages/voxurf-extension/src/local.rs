@@ -0,0 +1,175 @@
+use voxurf::grammar::{resolve_target, LocalCommand, ScrollDirection, Target};
+use voxurf::risk;
+
+use crate::error::Error;
+use crate::gate::{check_key, check_leaving, check_risk};
+use crate::glue::*;
+use crate::screen_reader::navigate_page;
+use crate::session::reset_session;
+use crate::settings::current_settings;
+use crate::settle::wait_until_settled;
+use crate::tree::{redact_tree, take_snapshot, PruningPolicy, Snapshot, FIELD_ROLES};
+use crate::undo::{capture_undo_step, push_undo_barrier, push_undo_step, undo};
+
+/// Runs the given command directly, without the LLM. This returns a description of what was
+/// done, or `None` if the command couldn't be run (e.g. because the element it refers to
+/// doesn't exist), in which case the LLM should be asked instead.
+pub async fn execute_local(command: &LocalCommand) -> Result<Option<String>, Error> {
+    let tab_id = current_tab_id().await?;
+    let description = match command {
+        LocalCommand::Back => {
+            check_leaving(tab_id, "").await?;
+            go_back(tab_id).await;
+            push_undo_barrier("Went back", "it went to another page").await;
+            Some("Went back".to_string())
+        }
+        LocalCommand::Forward => {
+            check_leaving(tab_id, "").await?;
+            go_forward(tab_id).await;
+            push_undo_barrier("Went forward", "it went to another page").await;
+            Some("Went forward".to_string())
+        }
+        LocalCommand::Reload => {
+            check_leaving(tab_id, "").await?;
+            reload_tab(tab_id).await;
+            push_undo_barrier("Reloaded the page", "it reloaded the page").await;
+            Some("Reloaded the page".to_string())
+        }
+        LocalCommand::Scroll(direction) => {
+            let (script, description) = match direction {
+                ScrollDirection::Up => (
+                    "window.scrollBy({ top: -window.innerHeight * 0.8, behavior: 'smooth' })",
+                    "Scrolled up",
+                ),
+                ScrollDirection::Down => (
+                    "window.scrollBy({ top: window.innerHeight * 0.8, behavior: 'smooth' })",
+                    "Scrolled down",
+                ),
+                ScrollDirection::Top => (
+                    "window.scrollTo({ top: 0, behavior: 'smooth' })",
+                    "Scrolled to the top",
+                ),
+                ScrollDirection::Bottom => (
+                    "window.scrollTo({ top: document.body.scrollHeight, behavior: 'smooth' })",
+                    "Scrolled to the bottom",
+                ),
+            };
+            attach_debugger(tab_id).await;
+            execute_js(tab_id, script).await;
+            detach_debugger(tab_id).await;
+            Some(description.to_string())
+        }
+        LocalCommand::Press(key) => {
+            check_key(tab_id, key.key(), "").await?;
+            attach_debugger(tab_id).await;
+            press_key(
+                tab_id,
+                key.key(),
+                key.code(),
+                key.key_code(),
+                key.text().map(|t| t.to_string()),
+            )
+            .await;
+            detach_debugger(tab_id).await;
+            let description = format!("Pressed {}", key.code());
+            // A key press could do anything to the page, so there's no telling what to put back
+            push_undo_barrier(&description, "I can't tell what pressing a key changed").await;
+            Some(description)
+        }
+        LocalCommand::Click(target) => act_on_target(tab_id, target, "click", "Clicked").await?,
+        LocalCommand::Focus(target) => act_on_target(tab_id, target, "focus", "Focused").await?,
+        LocalCommand::Navigate(nav_command) => Some(navigate_page(nav_command).await?),
+        LocalCommand::ResetConversation => {
+            reset_session().await;
+            Some("Forgot everything we've done so far".to_string())
+        }
+        LocalCommand::Undo(count) => Some(undo(*count).await?),
+    };
+    Ok(description)
+}
+
+/// Finds the element referred to by the given target, and calls the given method on it. This
+/// returns a description of what was done, using the given verb.
+async fn act_on_target(
+    tab_id: u32,
+    target: &Target,
+    method: &str,
+    verb: &str,
+) -> Result<Option<String>, Error> {
+    attach_debugger(tab_id).await;
+    // We only need actionable nodes, and we'll only resolve the one we want. If we know what
+    // kind of element it is, we only need to look for those, and for any fields, so we can
+    // tell if clicking might lose what's been typed into them.
+    let snapshot = if target.roles.is_empty() {
+        Snapshot::Full
+    } else {
+        let mut roles = target.roles.clone();
+        roles.extend(FIELD_ROLES);
+        Snapshot::Roles(roles)
+    };
+    let mut tree = take_snapshot(tab_id, &snapshot, &PruningPolicy::actionable_only()).await;
+    // Undo steps are kept in storage, so they mustn't have anything sensitive in them
+    redact_tree(tab_id, &mut tree).await;
+    let Some(node) = resolve_target(target, &tree) else {
+        detach_debugger(tab_id).await;
+        return Ok(None);
+    };
+    let description = format!(
+        "{verb} {}{}",
+        node.name.as_deref().unwrap_or("the element"),
+        node.role
+            .as_ref()
+            .map(|role| format!(" {role}"))
+            .unwrap_or_default()
+    );
+
+    // Clicks are sent as real mouse events, which pages can't tell apart from the user's own
+    let script = if method == "click" {
+        format!("await clickElement(elementFromId({}));", node.dom_id)
+    } else {
+        format!("elementFromId({}).{method}();", node.dom_id)
+    };
+    // Clicking can be just as destructive as anything the LLM does, and can change things
+    // that might need undoing
+    let undo_step = if method == "click" {
+        let risk = risk::assess("", &[node], &tree);
+        if let Err(err) = check_risk(tab_id, &risk, "").await {
+            detach_debugger(tab_id).await;
+            return Err(err);
+        }
+        Some(capture_undo_step(tab_id, &script, &description, &[node], &tree).await)
+    } else {
+        None
+    };
+
+    let settings = current_settings();
+    let result = run_script(
+        tab_id,
+        &script,
+        &[node.dom_id],
+        settings.waiting.timeout_ms,
+        settings.typing_delay_ms,
+    )
+    .await;
+    // The element might not be something that can be clicked (e.g. it's hidden), in which
+    // case the LLM might know better
+    let exception = js_sys::Reflect::get(&result, &"exception".into())
+        .ok()
+        .and_then(|e| e.as_string());
+    if let Some(_exception) = exception {
+        #[cfg(debug_assertions)]
+        log(&format!(
+            "Couldn't {method} node {}, falling back to the LLM: {_exception}",
+            node.dom_id
+        ));
+        detach_debugger(tab_id).await;
+        return Ok(None);
+    }
+    wait_until_settled(tab_id).await;
+    if let Some(undo_step) = undo_step {
+        push_undo_step(undo_step).await;
+    }
+    detach_debugger(tab_id).await;
+
+    Ok(Some(description))
+}
@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use sycamore::prelude::*;
+use voxurf::grammar::normalize;
+use voxurf::locator::{Locator, MIN_CONFIDENCE};
+use voxurf::Node;
+
+use crate::command::{element_regex, record_timings, run_step};
+use crate::error::Error;
+use crate::feedback::say;
+use crate::glue::*;
+use crate::history::{record, Event};
+use crate::session::note_action;
+use crate::settings::{site_of, Verbosity};
+use crate::settle::wait_until_settled;
+use crate::tree::{get_ax_tree, redact_tree, PruningPolicy};
+use crate::variables::load_usable_variables;
+
+/// The key the macros are stored under in extension storage.
+const MACROS_KEY: &str = "macros";
+
+/// A command that worked before, saved so it can be replayed without the LLM.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Macro {
+    /// A unique ID for the macro, which is when it was recorded, in milliseconds since the
+    /// Unix epoch.
+    pub id: f64,
+    /// What the user says to run the macro, which starts out as what they said when it was
+    /// recorded.
+    pub phrase: String,
+    /// The site the macro works on (e.g. `mail.example.com`). This can start with `*.` to
+    /// match any subdomain of a site.
+    pub site: String,
+    /// The steps of the macro, in order.
+    pub steps: Vec<MacroStep>,
+}
+
+/// One step of a macro, which is one script that was run on the page.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MacroStep {
+    /// The script, as the LLM wrote it, with references to elements by their DOM IDs at the
+    /// time and placeholders for variables.
+    pub script: String,
+    /// The LLM's description of what the script does.
+    pub description: String,
+    /// The elements the script references.
+    pub targets: Vec<MacroTarget>,
+}
+
+/// An element referenced by a step of a macro, with enough information to find it again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MacroTarget {
+    /// The DOM ID the element had when the macro was recorded, which is how the script
+    /// refers to it.
+    pub dom_id: u32,
+    /// How to find the element again.
+    #[serde(flatten)]
+    pub locator: Locator,
+}
+
+/// Loads the user's macros from extension storage.
+pub async fn load_macros() -> Vec<Macro> {
+    let stored = storage_get("local", MACROS_KEY).await;
+    serde_wasm_bindgen::from_value(stored).unwrap_or_default()
+}
+
+/// Saves the given macros to extension storage.
+pub async fn save_macros(macros: &[Macro]) {
+    let value = serde_wasm_bindgen::to_value(macros).unwrap();
+    storage_set("local", MACROS_KEY, value).await;
+}
+
+/// Saves the given steps, which were just run successfully for the given utterance, as a
+/// macro for the current site. This replaces any macro with the same phrase on that site.
+pub async fn save_macro(utterance: &str, steps: Vec<MacroStep>) {
+    let Ok(tab_id) = current_tab_id().await else {
+        return;
+    };
+    let Some(site) = site_of(&get_tab_url(tab_id).await.as_string().unwrap_or_default()) else {
+        return;
+    };
+
+    let mut macros = load_macros().await;
+    let phrase = normalize(utterance);
+    macros.retain(|m| !(normalize(&m.phrase) == phrase && m.site == site));
+    macros.push(Macro {
+        id: js_sys::Date::now(),
+        phrase: utterance.to_string(),
+        site,
+        steps,
+    });
+    save_macros(&macros).await;
+}
+
+/// Checks whether or not the given site pattern (e.g. `example.com` or `*.example.com`)
+/// matches the given site.
+fn site_matches(pattern: &str, site: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => site == domain || site.ends_with(&format!(".{domain}")),
+        None => site == pattern,
+    }
+}
+
+/// Replays the macro for the given utterance on the current site, if there is one. This
+/// returns the description of the last step, or `None` if there's no matching macro or the
+/// elements it needs can't be found on the page anymore, in which case the LLM should be
+/// used instead. If that happens partway through, the steps before will already have been
+/// run.
+pub async fn replay_macro(utterance: &str) -> Result<Option<String>, Error> {
+    let tab_id = current_tab_id().await?;
+    let Some(site) = site_of(&get_tab_url(tab_id).await.as_string().unwrap_or_default()) else {
+        return Ok(None);
+    };
+    let phrase = normalize(utterance);
+    let Some(found) = load_macros()
+        .await
+        .into_iter()
+        .find(|m| normalize(&m.phrase) == phrase && site_matches(&m.site, &site))
+    else {
+        return Ok(None);
+    };
+    #[cfg(debug_assertions)]
+    log(&format!("Replaying macro for \"{}\"", found.phrase));
+
+    let mut variables = load_usable_variables().await;
+    let policy = PruningPolicy::default();
+    let mut description = None;
+    for (i, step) in found.steps.into_iter().enumerate() {
+        let tab_id = current_tab_id().await?;
+        attach_debugger(tab_id).await;
+        // Every step waits for the page to settle after it runs, so only the first needs to
+        // before it looks at the page
+        if i == 0 {
+            wait_until_settled(tab_id).await;
+        }
+        let snapshot_start = js_sys::Date::now();
+        let mut tree = get_ax_tree(tab_id, &policy).await;
+        // Macros are recorded from redacted trees, so they have to be matched against them
+        redact_tree(tab_id, &mut tree).await;
+        let snapshot_ms = js_sys::Date::now() - snapshot_start;
+
+        // Find the elements again, and point the script at their new IDs
+        let Some(script) = retarget_script(&step, &tree) else {
+            detach_debugger(tab_id).await;
+            #[cfg(debug_assertions)]
+            log("Macro no longer matches the page, falling back to the LLM");
+            return Ok(None);
+        };
+
+        let result = run_step(tab_id, &script, &step.description, &tree, &mut variables).await;
+        detach_debugger(tab_id).await;
+        let report = result?;
+        record_timings(snapshot_ms, None, report.timings);
+        // The page must have changed in a way the macro doesn't handle, which the LLM might
+        if let Some(error) = report.exception {
+            record(Event::ScriptFailed {
+                error,
+                console_errors: report.console_errors,
+                retrying: false,
+            });
+            #[cfg(debug_assertions)]
+            log("Macro step failed, falling back to the LLM");
+            return Ok(None);
+        }
+
+        say(&step.description, Verbosity::Verbose);
+        note_action(&step.description);
+        description = Some(step.description);
+    }
+
+    Ok(description)
+}
+
+/// Rewrites the given step's script to reference its elements by their IDs in the given
+/// tree, returning `None` if any of them can't be found confidently.
+fn retarget_script(step: &MacroStep, tree: &[Node]) -> Option<String> {
+    let mut new_ids = HashMap::new();
+    for target in &step.targets {
+        let found = target
+            .locator
+            .resolve(tree)
+            .filter(|m| m.confidence >= MIN_CONFIDENCE)?;
+        #[cfg(debug_assertions)]
+        log(&format!(
+            "Found macro target {} as {} with confidence {:.2}",
+            target.dom_id, found.node.dom_id, found.confidence
+        ));
+        new_ids.insert(target.dom_id, found.node.dom_id);
+    }
+
+    let mut missing = false;
+    let script = element_regex().replace_all(&step.script, |caps: &regex::Captures| {
+        let old_id: u32 = caps[1].parse().unwrap();
+        match new_ids.get(&old_id) {
+            Some(new_id) => format!("elementFromId({new_id})"),
+            None => {
+                missing = true;
+                String::new()
+            }
+        }
+    });
+    if missing {
+        None
+    } else {
+        Some(script.to_string())
+    }
+}
+
+/// Applies the given change to the macros in the given signal, then saves them.
+fn update_macros<'a>(
+    cx: Scope<'a>,
+    macros: &'a Signal<Vec<Macro>>,
+    change: impl FnOnce(&mut Vec<Macro>),
+) {
+    let mut updated = (*macros.get()).clone();
+    change(&mut updated);
+    macros.set(updated.clone());
+    sycamore::futures::spawn_local_scoped(cx, async move {
+        save_macros(&updated).await;
+    });
+}
+
+/// A panel that lets the user see, rename, and delete their macros.
+#[component]
+pub fn MacrosPanel<G: Html>(cx: Scope) -> View<G> {
+    let macros = create_signal(cx, Vec::<Macro>::new());
+
+    sycamore::futures::spawn_local_scoped(cx, async move {
+        macros.set(load_macros().await);
+    });
+
+    view! { cx,
+        details(class="mt-2") {
+            summary { "Macros" }
+            (if macros.get().is_empty() {
+                view! { cx, p { "Commands that work are saved here, so they can be done again without the language model." } }
+            } else {
+                view! { cx, }
+            })
+            ul {
+                Keyed(
+                    iterable = macros,
+                    key = |m| m.id.to_bits(),
+                    view = move |cx, m| {
+                        let id = m.id;
+                        let phrase = create_signal(cx, m.phrase.clone());
+                        let details = format!("on {}, {} steps", m.site, m.steps.len());
+                        view! { cx,
+                            li(class="mt-1") {
+                                label(class="block") {
+                                    "Phrase "
+                                    input(
+                                        type = "text",
+                                        bind:value = phrase,
+                                        on:change = move |_| {
+                                            let new_phrase = phrase.get().trim().to_string();
+                                            if !new_phrase.is_empty() {
+                                                update_macros(cx, macros, move |macros| {
+                                                    if let Some(m) = macros.iter_mut().find(|m| m.id == id) {
+                                                        m.phrase = new_phrase;
+                                                    }
+                                                });
+                                            }
+                                        }
+                                    ) {}
+                                }
+                                span { (details) }
+                                " "
+                                button(
+                                    class="underline",
+                                    on:click = move |_| {
+                                        update_macros(cx, macros, move |macros| macros.retain(|m| m.id != id));
+                                    }
+                                ) { "Delete" }
+                            }
+                        }
+                    }
+                )
+            }
+        }
+    }
+}
@@ -9,12 +9,14 @@ pub struct OpenAiApi;
 
 impl OpenAiApi {
     pub async fn call(prompt: &str) -> Result<String, gloo_net::Error> {
+        Self::chat(&[ChatMessage::user(prompt)]).await
+    }
+
+    /// Continues the given conversation with the LLM, returning its reply.
+    pub async fn chat(messages: &[ChatMessage]) -> Result<String, gloo_net::Error> {
         let body = ApiRequestBody {
             model: "gpt-3.5-turbo".to_string(),
-            messages: vec![ChatMessage {
-                role: "user".to_string(),
-                content: prompt.to_string(),
-            }],
+            messages: messages.to_vec(),
             // TODO: adjust for best results?
             temperature: 0.7,
         };
@@ -28,7 +30,7 @@ impl OpenAiApi {
             .text()
             .await?;
 
-        let response: ApiResponse = serde_json::from_str(&response_text).unwrap();
+        let response: ApiResponse = serde_json::from_str(&response_text)?;
 
         let total_content: String = response
             .choices
@@ -48,11 +50,28 @@ struct ApiRequestBody {
     temperature: f64,
 }
 
-#[derive(Serialize)]
-struct ChatMessage {
+/// A message in a conversation with the LLM.
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatMessage {
     role: String,
     content: String,
 }
+impl ChatMessage {
+    /// Creates a message from the user (i.e. us).
+    pub fn user(content: &str) -> Self {
+        Self {
+            role: "user".to_string(),
+            content: content.to_string(),
+        }
+    }
+    /// Creates a message from the LLM, for replaying its earlier replies in a conversation.
+    pub fn assistant(content: &str) -> Self {
+        Self {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+        }
+    }
+}
 
 #[derive(Deserialize, Debug)]
 struct ApiResponse {
@@ -66,6 +85,5 @@ struct Choice {
 
 #[derive(Deserialize, Debug)]
 struct Message {
-    role: String,
     content: String,
 }
@@ -0,0 +1,37 @@
+use crate::error::Error;
+use crate::glue::*;
+use crate::openai::OpenAiApi;
+use crate::tree::{get_ax_tree, redact_tree, PruningPolicy};
+use crate::variables::{load_usable_variables, mask_variables};
+
+/// Prompt for the LLM when answering questions about the page
+static READ_PROMPT: &str = include_str!("../prompts/read.txt");
+
+/// Answers the given question about the current page, without taking any actions on it. This
+/// returns the answer, suitable for reading aloud to the user.
+pub async fn answer_question(question: &str) -> Result<String, Error> {
+    let tab_id = current_tab_id().await?;
+    attach_debugger(tab_id).await;
+    let mut tree = get_ax_tree(tab_id, &PruningPolicy::content()).await;
+    redact_tree(tab_id, &mut tree).await;
+    // We don't need the debugger for anything else, so let the user get on with things
+    detach_debugger(tab_id).await;
+
+    let mut content_str = String::new();
+    for node in tree {
+        content_str.push_str(&node.into_content_string(0));
+        content_str.push('\n');
+    }
+    // The page might show any of the user's details, which the LLM mustn't see
+    let content_str = mask_variables(content_str.trim(), &load_usable_variables().await);
+    let prompt = READ_PROMPT
+        .replace("{{ page_content }}", &content_str)
+        .replace("{{ user_question }}", question);
+    #[cfg(debug_assertions)]
+    log(&prompt);
+
+    let answer = OpenAiApi::call(&prompt)
+        .await
+        .map_err(|source| Error::LlmRequest { source })?;
+    Ok(answer.trim().to_string())
+}
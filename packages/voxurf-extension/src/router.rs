@@ -0,0 +1,191 @@
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use voxurf::grammar::{self, LocalCommand};
+
+use crate::browser::navigate;
+use crate::command::execute_command;
+use crate::dictate::dictate;
+use crate::error::Error;
+use crate::glue::*;
+use crate::local::execute_local;
+use crate::macros::replay_macro;
+use crate::openai::OpenAiApi;
+use crate::read::answer_question;
+
+/// Prompt for the LLM when the rules can't work out what the user wants
+static CLASSIFY_PROMPT: &str = include_str!("../prompts/classify.txt");
+
+/// Words at the start of an utterance that mean the user is asking about the page.
+const READ_PREFIXES: &[&str] = &[
+    "what",
+    "what's",
+    "whats",
+    "how much",
+    "how many",
+    "which",
+    "who",
+    "where",
+    "when",
+    "why",
+    "is there",
+    "are there",
+    "does",
+    "do i",
+    "read",
+    "tell me",
+    "describe",
+    "summarise",
+    "summarize",
+];
+/// Utterances (or prefixes of them, followed by a space) that mean the user wants to
+/// navigate the browser.
+const NAVIGATE_PHRASES: &[&str] = &[
+    "go back",
+    "go forward",
+    "back",
+    "forward",
+    "reload",
+    "refresh",
+    "new tab",
+    "open a new tab",
+    "open new tab",
+    "close tab",
+    "close this tab",
+    "close the tab",
+];
+/// Words at the start of an utterance that mean the user wants something typed into the
+/// focused field (unless they say where to type it, in which case it's an action).
+const DICTATE_PREFIXES: &[&str] = &["dictate", "type", "write"];
+
+/// The different kinds of things a user might want.
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Intent {
+    /// Taking actions on the current page.
+    Act,
+    /// Answering a question about the current page.
+    Read,
+    /// Navigating the browser (e.g. going back, opening a tab).
+    Navigate,
+    /// Typing text into the focused field.
+    Dictate,
+}
+
+#[derive(Deserialize)]
+struct ClassifyResponse {
+    intent: Intent,
+}
+
+/// What came of handling an utterance.
+pub enum Outcome {
+    /// The user asked something, and this is the answer.
+    Answer(String),
+    /// What the user asked for was done, and this is a description of what was done, if
+    /// there is one.
+    Done(Option<String>),
+    /// What the user asked for was done, but the page was checked afterward and it couldn't
+    /// be confirmed that it worked. This is a description of what was done, if there is one.
+    Unconfirmed(Option<String>),
+}
+
+/// Handles the given utterance by working out what the user wants and sending it to the
+/// right handler.
+pub async fn handle_utterance(utterance: &str) -> Result<Outcome, Error> {
+    // Simple commands can be run straight away, without the LLM
+    if let Some(command) = grammar::parse(utterance) {
+        #[cfg(debug_assertions)]
+        log(&format!("Parsed utterance as local command {:?}", command));
+        if let Some(description) = execute_local(&command).await? {
+            // Navigation commands announce where the user is, which is closer to an answer
+            // than a description of an action
+            return Ok(match command {
+                LocalCommand::Navigate(_) => Outcome::Answer(description),
+                _ => Outcome::Done(Some(description)),
+            });
+        }
+    }
+
+    // Commands that have worked before can be replayed, unless the page has changed too much
+    if let Some(description) = replay_macro(utterance).await? {
+        return Ok(Outcome::Done(Some(description)));
+    }
+
+    let intent = classify(utterance).await?;
+    #[cfg(debug_assertions)]
+    log(&format!("Classified utterance as {:?}", intent));
+
+    let outcome = match intent {
+        Intent::Act => {
+            let completion = execute_command(utterance).await?;
+            if completion.confirmed == Some(false) {
+                Outcome::Unconfirmed(Some(completion.description))
+            } else {
+                Outcome::Done(Some(completion.description))
+            }
+        }
+        Intent::Read => Outcome::Answer(answer_question(utterance).await?),
+        Intent::Navigate => {
+            navigate(utterance).await?;
+            Outcome::Done(None)
+        }
+        Intent::Dictate => {
+            dictate(utterance).await?;
+            Outcome::Done(None)
+        }
+    };
+    Ok(outcome)
+}
+
+/// Works out what the user wants from the given utterance. This uses simple rules first,
+/// and only asks the LLM if they don't match.
+async fn classify(utterance: &str) -> Result<Intent, Error> {
+    if let Some(intent) = classify_by_rules(utterance) {
+        return Ok(intent);
+    }
+
+    let prompt = CLASSIFY_PROMPT.replace("{{ utterance }}", utterance);
+    let response = OpenAiApi::call(&prompt)
+        .await
+        .map_err(|source| Error::LlmRequest { source })?;
+    // If the LLM can't tell us, acting is the most general thing we can do
+    Ok(parse_json_response::<ClassifyResponse>(&response)
+        .map(|res| res.intent)
+        .unwrap_or(Intent::Act))
+}
+
+/// Tries to work out what the user wants using simple rules.
+fn classify_by_rules(utterance: &str) -> Option<Intent> {
+    let normalized = utterance.trim().trim_end_matches(['.', '!']).to_lowercase();
+    let starts_with_word =
+        |prefix: &str| normalized == prefix || normalized.starts_with(&format!("{prefix} "));
+
+    if NAVIGATE_PHRASES.iter().any(|p| starts_with_word(p))
+        || ((starts_with_word("open") || starts_with_word("go to"))
+            && Regex::new(r"\b[a-z0-9-]+\.[a-z]{2,}\b")
+                .unwrap()
+                .is_match(&normalized))
+    {
+        Some(Intent::Navigate)
+    } else if normalized.ends_with('?') || READ_PREFIXES.iter().any(|p| starts_with_word(p)) {
+        Some(Intent::Read)
+    } else if DICTATE_PREFIXES.iter().any(|p| starts_with_word(p))
+        && !normalized.contains(" into ")
+        && !normalized.contains(" in the ")
+    {
+        Some(Intent::Dictate)
+    } else {
+        None
+    }
+}
+
+/// Parses a JSON object out of the LLM's response, which might be wrapped in a code fence or
+/// surrounded by other text.
+pub fn parse_json_response<T: DeserializeOwned>(response: &str) -> Option<T> {
+    let start = response.find('{')?;
+    let end = response.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    serde_json::from_str(&response[start..=end]).ok()
+}
@@ -0,0 +1,65 @@
+use std::cell::RefCell;
+use voxurf::navigation::{self, NavCommand, VirtualCursor};
+
+use crate::error::Error;
+use crate::glue::*;
+use crate::tree::{get_ax_tree, PruningPolicy};
+
+thread_local! {
+    /// The virtual cursor, and the ID of the tab it's in. This is kept between commands so
+    /// the user can keep moving through the page.
+    static CURSOR: RefCell<(Option<u32>, VirtualCursor)> = RefCell::new((None, VirtualCursor::new()));
+}
+
+/// Runs the given navigation command, moving the virtual cursor and focusing whatever it
+/// lands on. This returns what should be announced to the user.
+pub async fn navigate_page(command: &NavCommand) -> Result<String, Error> {
+    let tab_id = current_tab_id().await?;
+    attach_debugger(tab_id).await;
+    // Headings and landmarks are only kept as context, so we need the default policy
+    let tree = get_ax_tree(tab_id, &PruningPolicy::default()).await;
+
+    // If the user has moved to another tab, start from the top of that one
+    let mut cursor = CURSOR.with(|c| {
+        let (cursor_tab_id, cursor) = &*c.borrow();
+        if *cursor_tab_id == Some(tab_id) {
+            cursor.clone()
+        } else {
+            VirtualCursor::new()
+        }
+    });
+
+    let (moved_to, announcement) = match command {
+        NavCommand::Next(category) => match cursor.move_by(&tree, *category, true) {
+            Some(node) => (Some(node.dom_id), navigation::announce(node)),
+            None => (None, format!("No more {}", category.plural())),
+        },
+        NavCommand::Previous(category) => match cursor.move_by(&tree, *category, false) {
+            Some(node) => (Some(node.dom_id), navigation::announce(node)),
+            None => (None, format!("No previous {}", category.plural())),
+        },
+        NavCommand::GoToLandmark(role) => match cursor.go_to_landmark(&tree, role) {
+            Some(node) => (Some(node.dom_id), navigation::announce(node)),
+            None => (
+                None,
+                "There's no landmark like that on this page".to_string(),
+            ),
+        },
+        NavCommand::List(category) => (
+            None,
+            navigation::announce_list(&navigation::list(&tree, *category), *category),
+        ),
+        NavCommand::ReadCurrent => match cursor.current(&tree) {
+            Some(node) => (None, navigation::read(node)),
+            None => (None, "Nothing is selected yet".to_string()),
+        },
+    };
+
+    if let Some(dom_id) = moved_to {
+        focus_node(tab_id, dom_id).await;
+    }
+    detach_debugger(tab_id).await;
+    CURSOR.with(|c| *c.borrow_mut() = (Some(tab_id), cursor));
+
+    Ok(announcement)
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::glue::*;
+use crate::openai::OpenAiApi;
+use crate::variables::{clear_captures, load_usable_variables, mask_variables};
+
+/// The key the session is stored under in extension storage. This is kept in session storage,
+/// so it's forgotten when the browser is closed.
+const SESSION_KEY: &str = "session";
+/// Roughly how many tokens the session can take up in a prompt before older turns are
+/// summarized.
+const TOKEN_BUDGET: usize = 1000;
+/// Roughly how many characters there are in a token.
+const CHARS_PER_TOKEN: usize = 4;
+/// How many of the most recent turns are always kept as they are, rather than summarized.
+const KEEP_RECENT: usize = 3;
+
+/// Prompt for the LLM to summarize older turns
+static SUMMARIZE_PROMPT: &str = include_str!("../prompts/summarize.txt");
+
+thread_local! {
+    /// The turn currently in progress, if there is one.
+    static CURRENT_TURN: RefCell<Option<Turn>> = const { RefCell::new(None) };
+}
+
+/// What the user has been doing recently, carried across commands so they can refer back to
+/// earlier ones (e.g. "now do the same for the next one").
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Session {
+    /// A summary of turns too old to keep in full, if there have been any.
+    pub summary: Option<String>,
+    /// The most recent turns, oldest first.
+    pub turns: Vec<Turn>,
+}
+
+/// One thing the user said, and what came of it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Turn {
+    /// What the user said.
+    pub utterance: String,
+    /// The page the user was on when they said it, without any query or fragment.
+    pub page: Option<String>,
+    /// Descriptions of the actions taken, in order.
+    pub actions: Vec<String>,
+    /// How it turned out (e.g. "done", or the error the user was told).
+    pub outcome: Option<String>,
+}
+
+/// Starts a new turn for the given utterance. Any turn that was never finished is discarded.
+pub async fn start_turn(utterance: &str) {
+    let page = match current_tab_id().await {
+        Ok(tab_id) => get_tab_url(tab_id)
+            .await
+            .as_string()
+            .and_then(|url| url.split(['?', '#']).next().map(|page| page.to_string())),
+        Err(_) => None,
+    };
+    let turn = Turn {
+        utterance: utterance.to_string(),
+        page,
+        actions: Vec::new(),
+        outcome: None,
+    };
+    CURRENT_TURN.with(|t| *t.borrow_mut() = Some(turn));
+}
+
+/// Notes the given action as having been taken in the current turn. If there isn't one, this
+/// does nothing.
+pub fn note_action(description: &str) {
+    CURRENT_TURN.with(|t| {
+        if let Some(turn) = t.borrow_mut().as_mut() {
+            if turn.actions.last().map(|a| a.as_str()) != Some(description) {
+                turn.actions.push(description.to_string());
+            }
+        }
+    });
+}
+
+/// Finishes the current turn with the given outcome, adding it to the session. If the session
+/// has outgrown its budget, older turns are summarized.
+pub async fn finish_turn(outcome: &str) {
+    let Some(mut turn) = CURRENT_TURN.with(|t| t.borrow_mut().take()) else {
+        return;
+    };
+    turn.outcome = Some(outcome.to_string());
+
+    let mut session = load_session().await;
+    session.turns.push(turn);
+    if describe_session(&session).len() / CHARS_PER_TOKEN > TOKEN_BUDGET {
+        summarize_older_turns(&mut session).await;
+    }
+    save_session(&session).await;
+}
+
+/// Forgets everything in the session, including the turn in progress and anything captured
+/// from pages, so the user can start afresh.
+pub async fn reset_session() {
+    CURRENT_TURN.with(|t| *t.borrow_mut() = None);
+    save_session(&Session::default()).await;
+    clear_captures().await;
+}
+
+/// Loads the session from extension storage.
+pub async fn load_session() -> Session {
+    let stored = storage_get("session", SESSION_KEY).await;
+    serde_wasm_bindgen::from_value(stored).unwrap_or_default()
+}
+
+/// Saves the given session to extension storage.
+async fn save_session(session: &Session) {
+    let value = serde_wasm_bindgen::to_value(session).unwrap();
+    storage_set("session", SESSION_KEY, value).await;
+}
+
+/// Describes the given session for the LLM.
+pub fn describe_session(session: &Session) -> String {
+    let mut parts = Vec::new();
+    if let Some(summary) = &session.summary {
+        parts.push(format!("Earlier: {summary}"));
+    }
+    if !session.turns.is_empty() {
+        parts.push(describe_turns(&session.turns));
+    }
+    if parts.is_empty() {
+        "None".to_string()
+    } else {
+        parts.join("\n\n")
+    }
+}
+
+/// Describes the given turns for the LLM, one per line.
+fn describe_turns(turns: &[Turn]) -> String {
+    let lines: Vec<_> = turns
+        .iter()
+        .map(|turn| {
+            let mut line = match &turn.page {
+                Some(page) => format!("- On {page}, the user said \"{}\".", turn.utterance),
+                None => format!("- The user said \"{}\".", turn.utterance),
+            };
+            if !turn.actions.is_empty() {
+                line.push_str(&format!(" Actions taken: {}.", turn.actions.join("; ")));
+            }
+            if let Some(outcome) = &turn.outcome {
+                line.push_str(&format!(" Outcome: {outcome}."));
+            }
+            line
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Summarizes all but the most recent turns of the given session into its summary. If the
+/// LLM can't be reached, the older turns are just dropped.
+async fn summarize_older_turns(session: &mut Session) {
+    if session.turns.len() <= KEEP_RECENT {
+        return;
+    }
+    let older: Vec<_> = session
+        .turns
+        .drain(..session.turns.len() - KEEP_RECENT)
+        .collect();
+    // What the user said, and what was done, can include their details
+    let variables = load_usable_variables().await;
+    let prompt = SUMMARIZE_PROMPT
+        .replace(
+            "{{ summary }}",
+            &mask_variables(session.summary.as_deref().unwrap_or("Nothing"), &variables),
+        )
+        .replace(
+            "{{ turns }}",
+            &mask_variables(&describe_turns(&older), &variables),
+        );
+    match OpenAiApi::call(&prompt).await {
+        Ok(summary) => session.summary = Some(summary.trim().to_string()),
+        Err(_err) => {
+            #[cfg(debug_assertions)]
+            log(&format!("Couldn't summarize the session: {_err}"));
+        }
+    }
+}
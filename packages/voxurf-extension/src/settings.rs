@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use sycamore::prelude::*;
+use voxurf::redact::RedactionRules;
+
+use crate::glue::*;
+
+/// The key the settings are stored under in extension storage.
+const SETTINGS_KEY: &str = "settings";
+/// How long to wait after the user last changed a setting before saving, in milliseconds.
+/// Sync storage only allows so many writes a minute, so this shouldn't be every keystroke.
+const SAVE_DELAY_MS: u32 = 500;
+
+thread_local! {
+    /// The user's settings, loaded when the extension starts.
+    static SETTINGS: RefCell<Settings> = RefCell::new(Settings::default());
+}
+
+/// How much Voxurf should say to the user.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Verbosity {
+    /// Only errors, answers, and questions for the user.
+    Quiet,
+    /// Also what was heard, what was done, and when things are finished.
+    Normal,
+    /// Also every intermediate step.
+    Verbose,
+}
+
+/// How long to wait for pages to settle after Voxurf does something on them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Waiting {
+    /// How long a page has to go without loading anything or changing before it's considered
+    /// settled, in milliseconds.
+    pub quiet_ms: u32,
+    /// How long a single network request can be open before it's assumed to be a long poll,
+    /// which only finishes when there's something new, and stops being waited for, in
+    /// milliseconds.
+    pub request_limit_ms: u32,
+    /// The longest to wait for a page to settle, or for an element a script is waiting for to
+    /// appear, in milliseconds.
+    pub timeout_ms: u32,
+}
+impl Default for Waiting {
+    fn default() -> Self {
+        Self {
+            quiet_ms: 500,
+            request_limit_ms: 3000,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// The user's settings for Voxurf.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// The name of the voice to speak with, or `None` for the browser's default.
+    pub voice: Option<String>,
+    /// The speaking rate, where 1.0 is normal speed.
+    pub rate: f64,
+    /// How much to say to the user.
+    pub verbosity: Verbosity,
+    /// The sites (e.g. `example.com`) on which low-risk actions, like submitting a form, can
+    /// go ahead without asking the user first. High-risk actions are always confirmed.
+    pub auto_approve_low_risk: Vec<String>,
+    /// What should be redacted from pages before they're sent to the LLM.
+    pub redaction: RedactionRules,
+    /// How long to wait for pages to settle.
+    pub waiting: Waiting,
+    /// How long to wait between each character when typing, in milliseconds, or zero to type
+    /// everything at once. Some sites only react to typing one key at a time.
+    pub typing_delay_ms: u32,
+    /// Whether or not to check the page once a command is done, to make sure it worked.
+    pub verify_goals: bool,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            voice: None,
+            rate: 1.0,
+            verbosity: Verbosity::Normal,
+            auto_approve_low_risk: Vec::new(),
+            redaction: RedactionRules::default(),
+            waiting: Waiting::default(),
+            typing_delay_ms: 0,
+            verify_goals: false,
+        }
+    }
+}
+
+/// Gets the user's current settings.
+pub fn current_settings() -> Settings {
+    SETTINGS.with(|s| s.borrow().clone())
+}
+
+/// Loads the user's settings from extension storage. If they've never saved any, or if the
+/// saved ones can't be read, this will fall back to the defaults.
+pub async fn load_settings() {
+    let stored = storage_get("sync", SETTINGS_KEY).await;
+    let settings = serde_wasm_bindgen::from_value(stored).unwrap_or_default();
+    SETTINGS.with(|s| *s.borrow_mut() = settings);
+}
+
+/// Saves the given settings to extension storage, making them the current ones.
+pub async fn save_settings(settings: Settings) {
+    let value = serde_wasm_bindgen::to_value(&settings).unwrap();
+    SETTINGS.with(|s| *s.borrow_mut() = settings);
+    storage_set("sync", SETTINGS_KEY, value).await;
+}
+
+/// Gets the site (i.e. the host) of the given URL, if it has one.
+pub fn site_of(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let host = rest.split(['/', '?', '#']).next()?;
+    // Strip any credentials and port
+    let host = host.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+/// A panel that lets the user change their settings, which are saved once they've stopped
+/// changing them.
+#[component]
+pub fn SettingsPanel<G: Html>(cx: Scope) -> View<G> {
+    let initial = current_settings();
+    let voice = create_signal(cx, initial.voice.unwrap_or_default());
+    let rate = create_signal(cx, initial.rate.to_string());
+    let verbosity = create_signal(
+        cx,
+        match initial.verbosity {
+            Verbosity::Quiet => "quiet",
+            Verbosity::Normal => "normal",
+            Verbosity::Verbose => "verbose",
+        }
+        .to_string(),
+    );
+    let redact_protected = create_signal(cx, initial.redaction.protected_fields);
+    let redact_autocomplete = create_signal(cx, initial.redaction.autocomplete_fields);
+    let redact_cards = create_signal(cx, initial.redaction.card_numbers);
+    let redact_ssns = create_signal(cx, initial.redaction.ssns);
+    let redact_emails = create_signal(cx, initial.redaction.emails);
+    // One pattern per line
+    let redact_custom = create_signal(cx, initial.redaction.custom_patterns.join("\n"));
+    // In seconds, which are easier for the user to think about
+    let settle_timeout =
+        create_signal(cx, (initial.waiting.timeout_ms as f64 / 1000.0).to_string());
+    let settle_quiet = create_signal(cx, initial.waiting.quiet_ms.to_string());
+    let request_limit = create_signal(
+        cx,
+        (initial.waiting.request_limit_ms as f64 / 1000.0).to_string(),
+    );
+    let typing_delay = create_signal(cx, initial.typing_delay_ms.to_string());
+    let verify_goals = create_signal(cx, initial.verify_goals);
+    let voices = create_signal(cx, Vec::<String>::new());
+    // The site the user is currently on, and whether or not low-risk actions are
+    // auto-approved there
+    let site = create_signal(cx, None::<String>);
+    let approve_here = create_signal(cx, false);
+    // Bumped on every change, so a pending save can tell it's been superseded
+    let changes = create_ref(cx, Cell::new(0u32));
+
+    sycamore::futures::spawn_local_scoped(cx, async move {
+        let available: Vec<String> =
+            serde_wasm_bindgen::from_value(get_voices().await).unwrap_or_default();
+        voices.set(available);
+    });
+    sycamore::futures::spawn_local_scoped(cx, async move {
+        if let Ok(tab_id) = current_tab_id().await {
+            let current_site = site_of(&get_tab_url(tab_id).await.as_string().unwrap_or_default());
+            approve_here.set(
+                current_site
+                    .as_ref()
+                    .is_some_and(|s| initial.auto_approve_low_risk.contains(s)),
+            );
+            site.set(current_site);
+        }
+    });
+
+    create_effect(cx, move || {
+        let mut auto_approve_low_risk = current_settings().auto_approve_low_risk;
+        if let Some(site) = &*site.get() {
+            auto_approve_low_risk.retain(|s| s != site);
+            if *approve_here.get() {
+                auto_approve_low_risk.push(site.clone());
+            }
+        }
+        let settings = Settings {
+            voice: Some(voice.get().to_string()).filter(|v| !v.is_empty()),
+            rate: rate.get().parse().unwrap_or(1.0),
+            verbosity: match verbosity.get().as_str() {
+                "quiet" => Verbosity::Quiet,
+                "verbose" => Verbosity::Verbose,
+                _ => Verbosity::Normal,
+            },
+            auto_approve_low_risk,
+            redaction: RedactionRules {
+                protected_fields: *redact_protected.get(),
+                autocomplete_fields: *redact_autocomplete.get(),
+                card_numbers: *redact_cards.get(),
+                ssns: *redact_ssns.get(),
+                emails: *redact_emails.get(),
+                custom_patterns: redact_custom
+                    .get()
+                    .lines()
+                    .map(|l| l.trim().to_string())
+                    .filter(|l| !l.is_empty())
+                    .collect(),
+            },
+            waiting: Waiting {
+                quiet_ms: settle_quiet
+                    .get()
+                    .parse()
+                    .unwrap_or(Waiting::default().quiet_ms),
+                request_limit_ms: request_limit
+                    .get()
+                    .parse::<f64>()
+                    .map(|secs| (secs * 1000.0).round() as u32)
+                    .unwrap_or(Waiting::default().request_limit_ms),
+                timeout_ms: settle_timeout
+                    .get()
+                    .parse::<f64>()
+                    .map(|secs| (secs * 1000.0).round() as u32)
+                    .unwrap_or(Waiting::default().timeout_ms),
+            },
+            typing_delay_ms: typing_delay.get().parse().unwrap_or(0),
+            verify_goals: *verify_goals.get(),
+        };
+        let change = changes.get() + 1;
+        changes.set(change);
+        // Nothing has changed when the panel is first shown, or when the current site is
+        // looked up
+        if settings == current_settings() {
+            return;
+        }
+        sycamore::futures::spawn_local_scoped(cx, async move {
+            sleep(SAVE_DELAY_MS).await;
+            if changes.get() == change {
+                save_settings(settings).await;
+            }
+        });
+    });
+
+    view! { cx,
+        details(class="mt-2") {
+            summary { "Settings" }
+            label(class="block") {
+                "Voice "
+                select(bind:value = voice) {
+                    option(value = "") { "Default" }
+                    Indexed(
+                        iterable = voices,
+                        view = |cx, name| {
+                            let label = name.clone();
+                            view! { cx, option(value = name) { (label) } }
+                        }
+                    )
+                }
+            }
+            label(class="block") {
+                "Speaking rate "
+                input(type = "number", min = "0.5", max = "3", step = "0.1", bind:value = rate) {}
+            }
+            label(class="block") {
+                "Verbosity "
+                select(bind:value = verbosity) {
+                    option(value = "quiet") { "Quiet" }
+                    option(value = "normal") { "Normal" }
+                    option(value = "verbose") { "Verbose" }
+                }
+            }
+            label(class="block") {
+                "Wait up to this many seconds for pages to finish loading "
+                input(type = "number", min = "0", max = "30", step = "0.5", bind:value = settle_timeout) {}
+            }
+            label(class="block") {
+                "Consider a page loaded once nothing's changed for this many milliseconds "
+                input(type = "number", min = "0", max = "5000", step = "100", bind:value = settle_quiet) {}
+            }
+            label(class="block") {
+                "Stop waiting for any one request after this many seconds, in case it's a long poll "
+                input(type = "number", min = "0.5", max = "30", step = "0.5", bind:value = request_limit) {}
+            }
+            label(class="block") {
+                "Type one character every this many milliseconds (0 types everything at once) "
+                input(type = "number", min = "0", max = "500", step = "10", bind:value = typing_delay) {}
+            }
+            label(class="block") {
+                input(type = "checkbox", bind:checked = verify_goals) {}
+                " Check that commands worked afterward (this is slower)"
+            }
+            (if let Some(site) = &*site.get() {
+                let site = site.clone();
+                view! { cx,
+                    label(class="block") {
+                        input(type = "checkbox", bind:checked = approve_here) {}
+                        " Don't ask before low-risk actions (like submitting forms) on "
+                        (site)
+                    }
+                }
+            } else {
+                view! { cx, }
+            })
+            fieldset(class="mt-2") {
+                legend { "Hide from the language model" }
+                label(class="block") {
+                    input(type = "checkbox", bind:checked = redact_protected) {}
+                    " Passwords"
+                }
+                label(class="block") {
+                    input(type = "checkbox", bind:checked = redact_autocomplete) {}
+                    " Payment details and one-time codes"
+                }
+                label(class="block") {
+                    input(type = "checkbox", bind:checked = redact_cards) {}
+                    " Anything that looks like a card number"
+                }
+                label(class="block") {
+                    input(type = "checkbox", bind:checked = redact_ssns) {}
+                    " Anything that looks like a social security number"
+                }
+                label(class="block") {
+                    input(type = "checkbox", bind:checked = redact_emails) {}
+                    " Anything that looks like an email address"
+                }
+                label(class="block") {
+                    "Other patterns to hide (regular expressions, one per line) "
+                    textarea(bind:value = redact_custom) {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use serde::Deserialize;
+
+use crate::glue::*;
+use crate::settings::current_settings;
+
+/// What [`wait_for_stable`] found.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Stability {
+    /// How long we waited, in milliseconds.
+    waited_ms: f64,
+    /// Whether or not the page actually settled before the timeout.
+    stable: bool,
+}
+
+/// Waits for the page in the given tab to settle, which is when it's gone for a while without
+/// loading anything or changing, so that anything loaded after an action (like a dialog, or
+/// the results of a search) is there before we look at the page again. This gives up after
+/// the timeout in the user's settings. The debugger must already be attached.
+///
+/// This returns how long it waited, in milliseconds.
+pub async fn wait_until_settled(tab_id: u32) -> f64 {
+    let waiting = current_settings().waiting;
+    let stability: Stability = serde_wasm_bindgen::from_value(
+        wait_for_stable(
+            tab_id,
+            waiting.quiet_ms,
+            waiting.request_limit_ms,
+            waiting.timeout_ms,
+        )
+        .await,
+    )
+    .unwrap_or_default();
+    #[cfg(debug_assertions)]
+    if !stability.stable {
+        log(&format!(
+            "Page still hadn't settled after {:.0}ms, carrying on anyway",
+            stability.waited_ms
+        ));
+    }
+
+    stability.waited_ms
+}
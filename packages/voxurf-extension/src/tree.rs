@@ -0,0 +1,548 @@
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use wasm_bindgen::prelude::*;
+
+use voxurf::redact::{redact, FieldAttributes};
+use voxurf::{flatten, Node};
+
+use crate::glue::*;
+use crate::history::{record, Event};
+use crate::settings::current_settings;
+
+/// Maximum number of iterations in tree reconstitution. This prevents infinite loops.
+const MAX_ITERS: usize = 50;
+
+/// Roles of landmarks and live regions, which give the LLM a sense of where things are on
+/// the page.
+const LANDMARK_ROLES: &[&str] = &[
+    "banner",
+    "navigation",
+    "main",
+    "contentinfo",
+    "complementary",
+    "region",
+    "search",
+    "form",
+    "dialog",
+    "alertdialog",
+    "alert",
+    "status",
+];
+/// Roles of headings.
+const HEADING_ROLES: &[&str] = &["heading"];
+/// Roles of labels that aren't linked to their controls (linked ones become the control's
+/// name anyway).
+const LABEL_ROLES: &[&str] = &["LabelText", "legend", "caption"];
+/// Roles of plain text on the page, such as error messages, prices, and the text next to
+/// buttons.
+const STATIC_TEXT_ROLES: &[&str] = &["StaticText"];
+/// Roles that give content its structure, like lists, tables, and images.
+const STRUCTURE_ROLES: &[&str] = &[
+    "paragraph",
+    "list",
+    "listitem",
+    "table",
+    "row",
+    "cell",
+    "columnheader",
+    "rowheader",
+    "image",
+    "figure",
+    "blockquote",
+    "term",
+    "definition",
+];
+/// Roles of fields whose HTML attributes should be checked to see if they hold something
+/// sensitive.
+pub const FIELD_ROLES: &[&str] = &["textbox", "searchbox", "combobox", "spinbutton"];
+
+/// A strategy for which part of the accessibility tree to get. Fetching only part of it cuts
+/// down the work (and the prompt) on huge pages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Snapshot {
+    /// The whole tree.
+    Full,
+    /// The tree down to the given depth.
+    Depth(u32),
+    /// Everything under the node with the given DOM ID.
+    Subtree(u32),
+    /// The node with the given DOM ID, along with its ancestors, siblings, and children, which
+    /// is enough to see what's around it without the rest of the page.
+    Partial(u32),
+    /// Every node with the given accessible name, anywhere on the page.
+    Name(String),
+    /// Every node with any of the given roles, anywhere on the page.
+    Roles(Vec<&'static str>),
+}
+
+/// A policy for which nodes that can't be acted on should be kept in the tree anyway, so the
+/// LLM has some context as to what the actionable nodes are near. Actionable (i.e. focusable)
+/// nodes are always kept.
+#[derive(Clone, Copy, Debug)]
+pub struct PruningPolicy {
+    /// Whether or not to keep landmarks (e.g. `main`, `navigation`) and live regions (e.g.
+    /// `alert`).
+    pub keep_landmarks: bool,
+    /// Whether or not to keep headings.
+    pub keep_headings: bool,
+    /// Whether or not to keep labels that aren't linked to any control.
+    pub keep_labels: bool,
+    /// Whether or not to keep static text. Text that's already part of the name of its
+    /// nearest kept ancestor (e.g. the text inside a button) is always dropped.
+    pub keep_static_text: bool,
+    /// Whether or not to keep structural nodes, like lists, tables, and images. These are
+    /// mostly useful for reading the page, rather than acting on it.
+    pub keep_structure: bool,
+}
+impl Default for PruningPolicy {
+    fn default() -> Self {
+        Self {
+            keep_landmarks: true,
+            keep_headings: true,
+            keep_labels: true,
+            keep_static_text: true,
+            keep_structure: false,
+        }
+    }
+}
+impl PruningPolicy {
+    /// A policy that keeps only actionable nodes.
+    pub fn actionable_only() -> Self {
+        Self {
+            keep_landmarks: false,
+            keep_headings: false,
+            keep_labels: false,
+            keep_static_text: false,
+            keep_structure: false,
+        }
+    }
+    /// A policy that keeps everything with meaningful content, for reading the page.
+    pub fn content() -> Self {
+        Self {
+            keep_landmarks: true,
+            keep_headings: true,
+            keep_labels: true,
+            keep_static_text: true,
+            keep_structure: true,
+        }
+    }
+    /// Determines whether or not a non-actionable node with the given role should be kept as
+    /// context.
+    fn keeps(&self, role: &str) -> bool {
+        (self.keep_landmarks && LANDMARK_ROLES.contains(&role))
+            || (self.keep_headings && HEADING_ROLES.contains(&role))
+            || (self.keep_labels && LABEL_ROLES.contains(&role))
+            || (self.keep_static_text && STATIC_TEXT_ROLES.contains(&role))
+            || (self.keep_structure && STRUCTURE_ROLES.contains(&role))
+    }
+}
+
+/// Parses the nodes out of the given raw accessibility tree (or part of one).
+fn parse_raw_tree(raw: JsValue) -> Vec<AxNode> {
+    let tree: AxTree = serde_wasm_bindgen::from_value(raw).unwrap();
+    tree.nodes
+}
+
+fn undefined() -> JsValueSerde {
+    JsValueSerde(JsValue::UNDEFINED)
+}
+
+fn js_value_to_string(val: JsValue) -> String {
+    let wrapped = format!("{:?}", val);
+    let output = wrapped.strip_prefix("JsValue(").unwrap();
+    let output = output.strip_suffix(')').unwrap();
+    let output = output.strip_prefix('"').unwrap_or(output);
+    let output = output.strip_suffix('"').unwrap_or(output);
+
+    output.to_string()
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct JsValueSerde(#[serde(with = "serde_wasm_bindgen::preserve")] JsValue);
+
+#[derive(Deserialize)]
+struct AxTree {
+    nodes: Vec<AxNode>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AxNode {
+    node_id: String,
+    ignored: bool,
+    role: Option<AxValue>,
+    name: Option<AxValue>,
+    description: Option<AxValue>,
+    value: Option<AxValue>,
+    properties: Option<Vec<AxProperty>>,
+    parent_id: Option<String>,
+    child_ids: Option<Vec<String>>,
+    #[serde(rename = "backendDOMNodeId")]
+    backend_dom_node_id: Option<u32>,
+}
+impl AxNode {
+    /// Converts this into an intermediate node, working out whether it should be kept using
+    /// the given policy. The given IDs are those of all the nodes in the snapshot, which tell
+    /// us whether any of this node's children were left out of it.
+    fn into_intermediate(
+        self,
+        policy: &PruningPolicy,
+        fetched: &HashSet<String>,
+    ) -> IntermediateNode {
+        let role = self.role.and_then(|val| val.value.0.as_string());
+        let name = self.name.and_then(|val| val.value.0.as_string());
+        let is_root = role.as_ref().is_some_and(|r| r == "RootWebArea");
+        let actionable = !self.ignored
+            && !is_root
+            && self.properties.as_ref().is_some_and(|props| {
+                props
+                    .iter()
+                    .any(|prop| prop.name == "focusable" && prop.value.value.0 == JsValue::TRUE)
+            });
+        // Context nodes are only worth keeping if they're part of the policy, and, if they're
+        // text, if they actually say something
+        let is_empty_text = role
+            .as_ref()
+            .is_some_and(|r| STATIC_TEXT_ROLES.contains(&r.as_str()))
+            && name.as_ref().is_none_or(|n| n.trim().is_empty());
+        let context = !actionable
+            && !self.ignored
+            && !is_root
+            && !is_empty_text
+            && role.as_ref().is_some_and(|r| policy.keeps(r));
+
+        let collapsed = self
+            .child_ids
+            .as_ref()
+            .is_some_and(|ids| ids.iter().any(|id| !fetched.contains(id)));
+
+        IntermediateNode {
+            remove: !actionable && !context,
+            collapsed,
+            actionable,
+            // Only ones that need the default will be later filtered out
+            dom_id: self.backend_dom_node_id.unwrap_or(0),
+            name,
+            description: self.description.and_then(|val| val.value.0.as_string()),
+            role,
+            value: self.value.map(|val| js_value_to_string(val.value.0)),
+            properties: self
+                .properties
+                .map(|props| {
+                    props
+                        .into_iter()
+                        // This is conveyed by whether or not the node has an ID, there's no
+                        // point in preserving it
+                        .filter(|prop| prop.name != "focusable")
+                        .map(|prop| (prop.name, js_value_to_string(prop.value.value.0)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            id: self.node_id,
+            parent_id: self.parent_id,
+            children: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct AxValue {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default = "undefined")]
+    value: JsValueSerde,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AxProperty {
+    name: String,
+    value: AxValue,
+}
+
+// This has information needed for nested tree reconstitution
+struct IntermediateNode {
+    id: String,
+    parent_id: Option<String>,
+    dom_id: u32,
+    name: Option<String>,
+    description: Option<String>,
+    role: Option<String>,
+    value: Option<String>,
+    properties: HashMap<String, String>,
+    // These are implanted by the parent ID property
+    children: Vec<IntermediateNode>,
+    // Whether or not this node can be acted upon (if not, it's only there for context)
+    actionable: bool,
+    // Whether or not this node should be removed from the tree structure
+    // as irrelevant
+    remove: bool,
+    // Whether or not some of this node's children were left out of the snapshot
+    collapsed: bool,
+}
+impl IntermediateNode {
+    fn into_final(self) -> Node {
+        Node {
+            dom_id: self.dom_id,
+            actionable: self.actionable,
+            name: self.name,
+            description: self.description,
+            role: self.role,
+            value: self.value,
+            properties: self.properties,
+            collapsed: self.collapsed,
+            children: self.children.into_iter().map(|n| n.into_final()).collect(),
+        }
+    }
+    /// Whether or not this node is static text.
+    fn is_static_text(&self) -> bool {
+        self.role
+            .as_ref()
+            .is_some_and(|r| STATIC_TEXT_ROLES.contains(&r.as_str()))
+    }
+}
+
+/// Gets the node at the provided location vector mutably, if there is one.
+fn get_tree_node_mut<'a>(elems: &'a mut [Node], loc: &[usize]) -> Option<&'a mut Node> {
+    let (first_loc, rest) = loc.split_first()?;
+    let node = elems.get_mut(*first_loc)?;
+    if rest.is_empty() {
+        Some(node)
+    } else {
+        get_tree_node_mut(&mut node.children, rest)
+    }
+}
+
+/// Recursively gets the children of the element with the provided location
+/// vector. This returns the children so we can abstract over returning the
+/// entire tree if necessary, as in the case of top-level hoisting.
+fn get_tree_children(elems: &mut Vec<Node>, mut loc: Vec<usize>) -> &mut Vec<Node> {
+    if loc.is_empty() {
+        return elems;
+    }
+
+    let first_loc = loc.remove(0);
+    if loc.is_empty() {
+        &mut elems.get_mut(first_loc).unwrap().children
+    } else {
+        get_tree_children(&mut elems.get_mut(first_loc).unwrap().children, loc)
+    }
+}
+
+/// Gets the node at the provided location vector, if there is one (there won't be for the
+/// top-level).
+fn get_tree_node<'a>(elems: &'a [Node], loc: &[usize]) -> Option<&'a Node> {
+    let (first_loc, rest) = loc.split_first()?;
+    let node = elems.get(*first_loc)?;
+    if rest.is_empty() {
+        Some(node)
+    } else {
+        get_tree_node(&node.children, rest)
+    }
+}
+
+/// Redacts anything sensitive (like passwords and card numbers) from the given tree,
+/// according to the user's redaction rules, so it can be sent to the LLM. Everything
+/// redacted is recorded in the history of the current run.
+pub async fn redact_tree(tab_id: u32, tree: &mut [Node]) {
+    let rules = current_settings().redaction;
+
+    // Whether or not a field is sensitive often depends on HTML attributes that aren't in
+    // the accessibility tree, so we need to get those for any fields that have values
+    let mut attributes = HashMap::new();
+    if rules.protected_fields || rules.autocomplete_fields {
+        let field_ids: Vec<_> = flatten(tree)
+            .into_iter()
+            .filter(|n| {
+                FIELD_ROLES.contains(&n.role.as_deref().unwrap_or(""))
+                    && n.value.as_deref().is_some_and(|v| !v.is_empty())
+            })
+            .map(|n| n.dom_id)
+            .collect();
+        // These are independent, so there's no need to wait for each one in turn
+        let raw_attributes = join_all(
+            field_ids
+                .iter()
+                .map(|&dom_id| get_attributes(tab_id, dom_id)),
+        )
+        .await;
+        for (dom_id, raw) in field_ids.into_iter().zip(raw_attributes) {
+            let raw: HashMap<String, String> =
+                serde_wasm_bindgen::from_value(raw).unwrap_or_default();
+            attributes.insert(
+                dom_id,
+                FieldAttributes {
+                    input_type: raw.get("type").cloned(),
+                    autocomplete: raw.get("autocomplete").cloned(),
+                },
+            );
+        }
+    }
+
+    for redaction in redact(tree, &rules, &attributes) {
+        #[cfg(debug_assertions)]
+        log(&format!(
+            "Redacted {} from node {}",
+            redaction.reason, redaction.dom_id
+        ));
+        record(Event::Redacted {
+            role: redaction.role,
+            name: redaction.name,
+            reason: redaction.reason.to_string(),
+        });
+    }
+}
+
+/// Gets the whole accessibility tree and filters it according to the given pruning policy.
+/// See [`take_snapshot`] for getting only part of it.
+pub async fn get_ax_tree(tab_id: u32, policy: &PruningPolicy) -> Vec<Node> {
+    take_snapshot(tab_id, &Snapshot::Full, policy).await
+}
+
+/// Gets part of the accessibility tree, according to the given strategy, and filters it
+/// according to the given pruning policy, reconstituting the nested structure of the nodes
+/// that are kept. Nodes whose parents weren't part of the snapshot become roots, and nodes
+/// some of whose children weren't part of it are marked as collapsed. Actionable nodes are
+/// referenced by their DOM IDs, which are only resolved to elements on the page when
+/// something acts on them, so this leaves the page untouched.
+pub async fn take_snapshot(tab_id: u32, snapshot: &Snapshot, policy: &PruningPolicy) -> Vec<Node> {
+    let raw_nodes = match snapshot {
+        Snapshot::Full => parse_raw_tree(get_raw_ax_tree(tab_id, None).await),
+        Snapshot::Depth(depth) => parse_raw_tree(get_raw_ax_tree(tab_id, Some(*depth)).await),
+        Snapshot::Subtree(dom_id) => {
+            parse_raw_tree(query_ax_tree(tab_id, Some(*dom_id), None, None).await)
+        }
+        Snapshot::Partial(dom_id) => parse_raw_tree(get_partial_ax_tree(tab_id, *dom_id).await),
+        Snapshot::Name(name) => {
+            parse_raw_tree(query_ax_tree(tab_id, None, None, Some(name.clone())).await)
+        }
+        Snapshot::Roles(roles) => {
+            // These are independent, so there's no need to wait for each one in turn
+            let results = join_all(
+                roles
+                    .iter()
+                    .map(|role| query_ax_tree(tab_id, None, Some(role.to_string()), None)),
+            )
+            .await;
+            let mut nodes = Vec::new();
+            for result in results {
+                for node in parse_raw_tree(result) {
+                    // Nodes can have more than one of the roles we're looking for
+                    if !nodes.iter().any(|n: &AxNode| n.node_id == node.node_id) {
+                        nodes.push(node);
+                    }
+                }
+            }
+            nodes
+        }
+    };
+    let fetched: HashSet<String> = raw_nodes.iter().map(|n| n.node_id.clone()).collect();
+    // Filter and parse the tree into our own `Node` struct; this will be "flat"
+    // in that each node will have references to its parents and so forth
+    let mut flat_tree: Vec<Option<IntermediateNode>> = raw_nodes
+        .into_iter()
+        // We'll change these to `None` as we go
+        .map(|raw| Some(raw.into_intermediate(policy, &fetched)))
+        .collect();
+
+    // The actual tree structure
+    let mut tree = Vec::new();
+    let mut num_actionable = 0;
+    // A map of IDs to locations within `tree` (gradually populated)
+    let mut nodes_ref_map: HashMap<String, Vec<usize>> = HashMap::new();
+    // Keep iterating back through again and again until there's nothing left
+    let mut iters = 0;
+    let mut num_inserted = 0;
+    while flat_tree.iter().any(|n| n.is_some()) && iters < MAX_ITERS {
+        for node_opt in flat_tree.iter_mut() {
+            if let Some(mut node) = node_opt.take() {
+                // If this node's parent isn't in the snapshot, it's a root
+                let parent_id = node
+                    .parent_id
+                    .as_ref()
+                    .filter(|parent_id| fetched.contains(*parent_id));
+                if let Some(parent_id) = parent_id {
+                    if let Some(parent_loc_ref) = nodes_ref_map.get(parent_id).cloned() {
+                        // Text that's already part of the name of its nearest kept ancestor
+                        // (e.g. the text inside a button or heading) is just noise
+                        if !node.remove && node.is_static_text() {
+                            let text = node.name.as_deref().unwrap_or("").trim();
+                            if get_tree_node(&tree, &parent_loc_ref)
+                                .and_then(|parent| parent.name.as_deref())
+                                .is_some_and(|parent_name| parent_name.contains(text))
+                            {
+                                node.remove = true;
+                            }
+                        }
+
+                        // If this node's children were left out, the nearest kept ancestor
+                        // is the one to expand to get them
+                        if node.remove && node.collapsed {
+                            if let Some(parent) = get_tree_node_mut(&mut tree, &parent_loc_ref) {
+                                parent.collapsed = true;
+                            }
+                        }
+
+                        // This is the vector we're inserting our child into
+                        let parent_children = get_tree_children(&mut tree, parent_loc_ref.clone());
+
+                        let id = node.id.clone();
+                        // If this node should be removed, then we'll insert children
+                        // in the same place this was going to be inserted. Otherwise,
+                        // they'll be inserted within this node.
+                        let child_insertion_loc = if node.remove {
+                            parent_loc_ref
+                        } else {
+                            num_inserted += 1;
+                            if node.actionable {
+                                num_actionable += 1;
+                            }
+                            parent_children.push(node.into_final());
+                            let mut self_loc = parent_loc_ref;
+                            self_loc.push(parent_children.len() - 1);
+                            self_loc
+                        };
+                        nodes_ref_map.insert(id, child_insertion_loc);
+                    } else {
+                        // Parent isn't in the tree yet, leave this node behind;
+                        // we'll get it on the next pass
+                        *node_opt = Some(node);
+                    }
+                } else {
+                    let id = node.id.clone();
+                    // If this node should be removed, then we'll insert children at
+                    // the top-level, otherwise within this node in the tree
+                    let child_insertion_loc = if node.remove {
+                        Vec::new()
+                    } else {
+                        num_inserted += 1;
+                        if node.actionable {
+                            num_actionable += 1;
+                        }
+                        tree.push(node.into_final());
+                        vec![tree.len() - 1]
+                    };
+                    nodes_ref_map.insert(id, child_insertion_loc);
+                }
+            }
+        }
+        iters += 1;
+    }
+    if flat_tree.iter().any(|n| n.is_some()) {
+        panic!(
+            "failed to reconstitute nested accessibility tree after {} iterations",
+            MAX_ITERS
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    log(&format!(
+        "Total nodes {} reduced to {} relevant nodes ({} actionable)",
+        nodes_ref_map.len(),
+        num_inserted,
+        num_actionable
+    ));
+
+    tree
+}
@@ -0,0 +1,276 @@
+use voxurf::locator::{Locator, MIN_CONFIDENCE};
+use voxurf::redact::MASK;
+use voxurf::undo::{
+    is_barrier, pop_step, push_step, why_not_undoable, Blocked, ElementState, UndoStep, UndoTarget,
+};
+use voxurf::{flatten, Node};
+
+use crate::error::Error;
+use crate::glue::*;
+use crate::settings::current_settings;
+use crate::settle::wait_until_settled;
+use crate::tree::{get_ax_tree, redact_tree, PruningPolicy};
+
+/// The key the steps that can be undone are stored under in extension storage. These are
+/// kept in session storage, so they're forgotten when the browser is closed.
+const UNDO_KEY: &str = "undo";
+
+/// A script for [`run_script`] that gets the state of each of the elements with the DOM IDs
+/// in `{{ ids }}` as JSON, in the same order. Native checkboxes and radio buttons, and
+/// anything with `aria-checked`, have their checked state recorded, and everything else its
+/// value.
+static CAPTURE_SCRIPT: &str = r#"
+return JSON.stringify({{ ids }}.map(id => {
+  const el = elementFromId(id);
+  if (!el) return null;
+  if (el.type === "checkbox" || el.type === "radio") return { checked: el.checked };
+  const ariaChecked = el.getAttribute("aria-checked");
+  if (ariaChecked !== null) return { checked: ariaChecked === "true" };
+  if (el.isContentEditable) return { value: el.textContent };
+  if ("value" in el) return { value: String(el.value) };
+  return null;
+}));
+"#;
+/// A helper for putting an element back into a state recorded by [`CAPTURE_SCRIPT`]. This
+/// clicks and types just like a step would, so frameworks that ignore values set directly still
+/// notice the change. Options of a native `select` can't be typed, so those are picked with the
+/// arrow keys instead.
+static RESTORE_HELPER: &str = r#"
+const __restore = async (el, state) => {
+  if (!el) throw new Error("The element is gone");
+  if (state.checked !== null) {
+    const checked = el.type === "checkbox" || el.type === "radio"
+      ? el.checked
+      : el.getAttribute("aria-checked") === "true";
+    if (checked !== state.checked) await clickElement(el);
+  } else if (state.value !== null) {
+    if (el instanceof HTMLSelectElement) {
+      const index = Array.from(el.options).findIndex(option => option.value === state.value);
+      if (index === -1 || index === el.selectedIndex) return;
+      el.focus();
+      await pressKey("Home");
+      for (let i = 0; i < index; i++) await pressKey("ArrowDown");
+    } else {
+      await typeInto(el, state.value);
+    }
+  }
+};
+"#;
+
+/// Records what the given targets of the given script are like before it runs, so it can be
+/// undone later. If the script can't be undone, the reason why is recorded instead. The given
+/// tree must already be redacted, and the debugger must already be attached.
+pub async fn capture_undo_step(
+    tab_id: u32,
+    script: &str,
+    description: &str,
+    targets: &[&Node],
+    tree: &[Node],
+) -> UndoStep {
+    let mut step = UndoStep {
+        description: description.to_string(),
+        not_undoable: why_not_undoable(script, targets),
+        barrier: is_barrier(script, targets),
+        targets: Vec::new(),
+    };
+    if step.not_undoable.is_some() {
+        return step;
+    }
+    // Anything hidden from the LLM shouldn't be kept in storage either, and without it there's
+    // no putting the field back, or anything done after it
+    if targets
+        .iter()
+        .any(|t| t.value.as_deref().is_some_and(|v| v.contains(MASK)))
+    {
+        step.not_undoable = Some("I don't keep copies of private information".to_string());
+        step.barrier = true;
+        return step;
+    }
+
+    let ids: Vec<_> = targets.iter().map(|n| n.dom_id).collect();
+    let capture_script = CAPTURE_SCRIPT.replace("{{ ids }}", &format!("{ids:?}"));
+    let result = run_script(
+        tab_id,
+        &capture_script,
+        &ids,
+        current_settings().waiting.timeout_ms,
+        0,
+    )
+    .await;
+    let states: Option<Vec<Option<ElementState>>> = js_sys::Reflect::get(&result, &"result".into())
+        .ok()
+        .and_then(|r| r.as_string())
+        .and_then(|r| serde_json::from_str(&r).ok());
+    let Some(states) = states else {
+        step.not_undoable = Some("I couldn't tell what it changed".to_string());
+        return step;
+    };
+
+    for (node, state) in targets.iter().zip(states) {
+        let (Some(state), Some(locator)) = (state, Locator::for_node(tree, node.dom_id)) else {
+            step.not_undoable = Some("I couldn't tell what it changed".to_string());
+            step.targets = Vec::new();
+            return step;
+        };
+        step.targets.push(UndoTarget {
+            dom_id: node.dom_id,
+            locator,
+            state,
+        });
+    }
+    step
+}
+
+/// Remembers the given step, so it can be undone later.
+pub async fn push_undo_step(step: UndoStep) {
+    let mut steps = load_undo_steps().await;
+    push_step(&mut steps, step);
+    save_undo_steps(&steps).await;
+}
+
+/// Remembers that something with the given description was done that can't be undone for
+/// the given reason, and that changed the page so much nothing before it can be either (e.g.
+/// going back).
+pub async fn push_undo_barrier(description: &str, reason: &str) {
+    push_undo_step(UndoStep::barrier(description, reason)).await;
+}
+
+/// Undoes up to the given number of the most recent steps, newest first, returning a
+/// description of what was undone. This stops at the first step that can't be undone, which
+/// is forgotten so that undoing again will carry on past it, unless it changed the page too
+/// much for that.
+pub async fn undo(count: usize) -> Result<String, Error> {
+    let mut steps = load_undo_steps().await;
+    if steps.is_empty() {
+        return Err(Error::NothingToUndo);
+    }
+
+    let tab_id = current_tab_id().await?;
+    attach_debugger(tab_id).await;
+    // The steps' locators were made from redacted trees, so they have to be matched against one
+    let mut tree = get_ax_tree(tab_id, &PruningPolicy::default()).await;
+    redact_tree(tab_id, &mut tree).await;
+    let mut undone = Vec::new();
+    let mut problem = None;
+    while undone.len() < count {
+        let step = match pop_step(&mut steps) {
+            Some(Ok(step)) => step,
+            Some(Err(blocked)) => {
+                problem = Some(blocked);
+                break;
+            }
+            None => break,
+        };
+        match restore_step(tab_id, &step, &tree).await {
+            Ok(()) => undone.push(step.description),
+            Err(reason) => {
+                problem = Some(Blocked {
+                    description: step.description,
+                    reason,
+                });
+                break;
+            }
+        }
+    }
+    if !undone.is_empty() {
+        wait_until_settled(tab_id).await;
+    }
+    detach_debugger(tab_id).await;
+    save_undo_steps(&steps).await;
+
+    let mut description = match undone.as_slice() {
+        [] => {
+            return Err(match problem {
+                Some(blocked) => Error::CantUndo {
+                    description: describe_step(&blocked.description),
+                    reason: blocked.reason,
+                },
+                None => Error::NothingToUndo,
+            });
+        }
+        [only] => format!("Undid {}", describe_step(only)),
+        _ => format!("Undid the last {} steps", undone.len()),
+    };
+    match problem {
+        Some(blocked) => description.push_str(&format!(
+            ", but I can't undo {}, because {}",
+            describe_step(&blocked.description),
+            blocked.reason
+        )),
+        None if undone.len() < count => {
+            description.push_str(", which is everything I can undo");
+        }
+        None => {}
+    }
+    Ok(description)
+}
+
+/// Puts the elements of the given step back the way they were, finding them in the given
+/// tree. If they can't be, this returns the reason why.
+async fn restore_step(tab_id: u32, step: &UndoStep, tree: &[Node]) -> Result<(), String> {
+    let nodes = flatten(tree);
+    let mut ids = Vec::new();
+    let mut script = RESTORE_HELPER.to_string();
+    // Later changes could have been made on top of earlier ones in the same step
+    for target in step.targets.iter().rev() {
+        let id = match nodes
+            .iter()
+            .find(|n| n.dom_id == target.dom_id && n.actionable)
+        {
+            Some(node) => node.dom_id,
+            None => target
+                .locator
+                .resolve(tree)
+                .filter(|m| m.confidence >= MIN_CONFIDENCE)
+                .map(|m| m.node.dom_id)
+                .ok_or("what it changed isn't on the page anymore")?,
+        };
+        ids.push(id);
+        script.push_str(&format!(
+            "await __restore(elementFromId({id}), {});\n",
+            serde_json::to_string(&target.state).unwrap()
+        ));
+    }
+
+    let settings = current_settings();
+    let result = run_script(
+        tab_id,
+        &script,
+        &ids,
+        settings.waiting.timeout_ms,
+        settings.typing_delay_ms,
+    )
+    .await;
+    let exception = js_sys::Reflect::get(&result, &"exception".into())
+        .ok()
+        .and_then(|e| e.as_string());
+    match exception {
+        Some(_exception) => {
+            #[cfg(debug_assertions)]
+            log(&format!("Couldn't undo a step: {_exception}"));
+            Err("something went wrong on the page".to_string())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Describes the step with the given description so it can be read out.
+fn describe_step(description: &str) -> String {
+    if description.is_empty() {
+        "that step".to_string()
+    } else {
+        format!("\"{description}\"")
+    }
+}
+
+/// Loads the steps that can be undone from extension storage, oldest first.
+async fn load_undo_steps() -> Vec<UndoStep> {
+    let stored = storage_get("session", UNDO_KEY).await;
+    serde_wasm_bindgen::from_value(stored).unwrap_or_default()
+}
+
+/// Saves the given steps that can be undone to extension storage.
+async fn save_undo_steps(steps: &[UndoStep]) {
+    let value = serde_wasm_bindgen::to_value(steps).unwrap();
+    storage_set("session", UNDO_KEY, value).await;
+}
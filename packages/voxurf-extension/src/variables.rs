@@ -0,0 +1,271 @@
+use regex::{Captures, Regex};
+use std::collections::BTreeMap;
+use sycamore::prelude::*;
+use voxurf::validate::{placeholders_in_strings, unquote_placeholders, PLACEHOLDER_PATTERN};
+use voxurf::Node;
+
+use crate::error::Error;
+use crate::glue::*;
+
+/// The key the variables are stored under in extension storage. These are kept in local
+/// storage, rather than synced, because they're likely to be personal.
+const VARIABLES_KEY: &str = "variables";
+/// The key values captured from pages are stored under in extension storage. These are kept
+/// apart from the user's own variables, in session storage, so they're forgotten when the
+/// browser is closed and can never replace anything the user saved.
+const CAPTURES_KEY: &str = "captures";
+/// The minimum length of a variable's value for it to be masked out of the page before it's
+/// sent to the LLM. Anything shorter is likely to appear on the page by coincidence.
+const MIN_MASKED_LEN: usize = 3;
+/// The name of the variable that holds whatever's on the user's clipboard. This isn't
+/// stored, it's read from the clipboard whenever it's used.
+const CLIPBOARD_VARIABLE: &str = "clipboard";
+
+/// Loads the user's variables from extension storage, as a map of names to values.
+pub async fn load_variables() -> BTreeMap<String, String> {
+    let stored = storage_get("local", VARIABLES_KEY).await;
+    serde_wasm_bindgen::from_value(stored).unwrap_or_default()
+}
+
+/// Saves the given variables to extension storage.
+pub async fn save_variables(variables: &BTreeMap<String, String>) {
+    let value = serde_wasm_bindgen::to_value(variables).unwrap();
+    storage_set("local", VARIABLES_KEY, value).await;
+}
+
+/// Loads the values captured from pages during this session, as a map of names to values.
+pub async fn load_captures() -> BTreeMap<String, String> {
+    let stored = storage_get("session", CAPTURES_KEY).await;
+    serde_wasm_bindgen::from_value(stored).unwrap_or_default()
+}
+
+/// Saves the given captured values to extension storage.
+async fn save_captures(captures: &BTreeMap<String, String>) {
+    let value = serde_wasm_bindgen::to_value(captures).unwrap();
+    storage_set("session", CAPTURES_KEY, value).await;
+}
+
+/// Forgets every value captured from pages.
+pub async fn clear_captures() {
+    save_captures(&BTreeMap::new()).await;
+}
+
+/// Loads every variable a script can use, which is the user's own along with anything
+/// captured from pages during this session.
+pub async fn load_usable_variables() -> BTreeMap<String, String> {
+    let mut variables = load_captures().await;
+    // The user's own always win, though captures shouldn't ever share their names
+    variables.extend(load_variables().await);
+    variables
+}
+
+/// Turns what the user typed as a variable name into one the LLM can reliably reference.
+fn normalize_name(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Stores the given values captured from the page as variables, both in the given map (so
+/// later trips can use them) and in session storage (so later commands can). Values can't be
+/// captured under the names of the user's own variables, so any that are are left out, and
+/// their names returned.
+pub async fn store_captures(
+    captures: BTreeMap<String, String>,
+    variables: &mut BTreeMap<String, String>,
+) -> Vec<String> {
+    if captures.is_empty() {
+        return Vec::new();
+    }
+    let saved = load_variables().await;
+    let mut stored = load_captures().await;
+    let mut refused = Vec::new();
+    for (name, value) in captures {
+        let name = normalize_name(&name);
+        if name == CLIPBOARD_VARIABLE || saved.contains_key(&name) {
+            refused.push(name);
+            continue;
+        }
+        variables.insert(name.clone(), value.clone());
+        stored.insert(name, value);
+    }
+    save_captures(&stored).await;
+    refused
+}
+
+/// Lists the names of the given variables for the LLM, without their values.
+pub fn describe_variables(variables: &BTreeMap<String, String>) -> String {
+    let mut lines: Vec<_> = variables
+        .keys()
+        .filter(|name| *name != CLIPBOARD_VARIABLE)
+        .map(|name| format!("- `{{{{var:{name}}}}}`"))
+        .collect();
+    lines.push(format!(
+        "- `{{{{var:{CLIPBOARD_VARIABLE}}}}}` (whatever the user has copied)"
+    ));
+    lines.join("\n")
+}
+
+/// Replaces any values of the given variables that appear in the given text (e.g. an email
+/// address the user has already typed into a form) with their placeholders, so they aren't
+/// sent to the LLM.
+pub fn mask_variables(text: &str, variables: &BTreeMap<String, String>) -> String {
+    let mut masked = text.to_string();
+    // Replace longer values first, in case one contains another
+    let mut by_len: Vec<_> = variables
+        .iter()
+        .filter(|(_, value)| value.len() >= MIN_MASKED_LEN)
+        .collect();
+    by_len.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+    for (name, value) in by_len {
+        masked = masked.replace(value.as_str(), &format!("{{{{var:{name}}}}}"));
+    }
+    masked
+}
+
+/// Masks the values of the given variables out of the values of every node in the given tree,
+/// in the same way as [`mask_variables`].
+pub fn mask_tree_values(tree: &mut [Node], variables: &BTreeMap<String, String>) {
+    for node in tree {
+        if let Some(value) = &node.value {
+            node.value = Some(mask_variables(value, variables));
+        }
+        mask_tree_values(&mut node.children, variables);
+    }
+}
+
+/// Gets the regular expression for variable placeholders in scripts.
+fn placeholder_regex() -> Regex {
+    Regex::new(PLACEHOLDER_PATTERN).unwrap()
+}
+
+/// Gets the names of any variables the given script uses that don't exist, as they're
+/// written in the script.
+pub fn unknown_variables(script: &str, variables: &BTreeMap<String, String>) -> Vec<String> {
+    let mut unknown = Vec::new();
+    for caps in placeholder_regex().captures_iter(script) {
+        let name = normalize_name(&caps[1]);
+        if name != CLIPBOARD_VARIABLE
+            && !variables.contains_key(&name)
+            && !unknown.contains(&caps[1].to_string())
+        {
+            unknown.push(caps[1].to_string());
+        }
+    }
+    unknown
+}
+
+/// Substitutes the real values of the given variables into the given script in place of
+/// their placeholders, each as a complete JS string literal. Values can come from the page, so
+/// placeholders inside strings or comments are refused, since a value there could break out of
+/// them and run as code. The clipboard is only read if the script uses it.
+pub async fn substitute_variables(
+    script: &str,
+    variables: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    let script = &unquote_placeholders(script);
+    if !placeholders_in_strings(script).is_empty() {
+        return Err(Error::ForbiddenScript);
+    }
+    let re = placeholder_regex();
+    let uses_clipboard = re
+        .captures_iter(script)
+        .any(|caps| normalize_name(&caps[1]) == CLIPBOARD_VARIABLE);
+    let clipboard = if uses_clipboard {
+        Some(read_clipboard().await.as_string().unwrap_or_default())
+    } else {
+        None
+    };
+
+    let mut unknown = None;
+    let substituted = re.replace_all(script, |caps: &Captures| {
+        let name = normalize_name(&caps[1]);
+        let value = if name == CLIPBOARD_VARIABLE {
+            clipboard.as_ref()
+        } else {
+            variables.get(&name)
+        };
+        match value {
+            Some(value) => serde_json::to_string(value).unwrap(),
+            None => {
+                unknown = Some(caps[1].to_string());
+                String::new()
+            }
+        }
+    });
+
+    match unknown {
+        Some(name) => Err(Error::UnknownVariable { name }),
+        None => Ok(substituted.to_string()),
+    }
+}
+
+/// A panel that lets the user add, change, and remove their variables. Values are never
+/// shown once saved.
+#[component]
+pub fn VariablesPanel<G: Html>(cx: Scope) -> View<G> {
+    let variables = create_signal(cx, BTreeMap::<String, String>::new());
+    let new_name = create_signal(cx, String::new());
+    let new_value = create_signal(cx, String::new());
+
+    sycamore::futures::spawn_local_scoped(cx, async move {
+        variables.set(load_variables().await);
+    });
+
+    let add = move |_| {
+        let name = normalize_name(&new_name.get());
+        if name.is_empty() {
+            return;
+        }
+        let mut updated = (*variables.get()).clone();
+        updated.insert(name, new_value.get().to_string());
+        new_name.set(String::new());
+        new_value.set(String::new());
+        variables.set(updated.clone());
+        sycamore::futures::spawn_local_scoped(cx, async move {
+            save_variables(&updated).await;
+        });
+    };
+
+    view! { cx,
+        details(class="mt-2") {
+            summary { "Variables" }
+            ul {
+                Keyed(
+                    iterable = create_memo(cx, || variables.get().keys().cloned().collect::<Vec<_>>()),
+                    key = |name| name.clone(),
+                    view = move |cx, name| {
+                        let label = name.clone();
+                        view! { cx,
+                            li(class="flex gap-2") {
+                                span { (label) }
+                                button(
+                                    class="underline",
+                                    on:click = move |_| {
+                                        let mut updated = (*variables.get()).clone();
+                                        updated.remove(&name);
+                                        variables.set(updated.clone());
+                                        sycamore::futures::spawn_local_scoped(cx, async move {
+                                            save_variables(&updated).await;
+                                        });
+                                    }
+                                ) { "Remove" }
+                            }
+                        }
+                    }
+                )
+            }
+            label(class="block") {
+                "Name "
+                input(type = "text", placeholder = "email", bind:value = new_name) {}
+            }
+            label(class="block") {
+                "Value "
+                input(type = "password", bind:value = new_value) {}
+            }
+            button(class="rounded bg-slate-200 px-2 py-1", on:click = add) { "Save variable" }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Differences between two snapshots of the same page, so that after an action only what's
+//! changed needs to be described, rather than the whole page again.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::node::{flatten, Node};
+
+/// The differences between two snapshots of a page. Nodes are matched up by their DOM IDs,
+/// and nodes without one are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct TreeDiff<'a> {
+    /// Nodes that are only in the new snapshot.
+    pub added: Vec<&'a Node>,
+    /// Nodes that are only in the old snapshot.
+    pub removed: Vec<&'a Node>,
+    /// Nodes that are in both, as they are in the new snapshot, along with the names of
+    /// what's changed about them (e.g. `value`).
+    pub changed: Vec<(&'a Node, Vec<String>)>,
+}
+impl TreeDiff<'_> {
+    /// Whether or not nothing has changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+    /// The total number of nodes that have been added, removed, or changed.
+    pub fn len(&self) -> usize {
+        self.added.len() + self.removed.len() + self.changed.len()
+    }
+    /// Converts the diff into a string suitable for LLM ingestion, in the same format as
+    /// [`Node::into_string`], but with each node on its own line without its children.
+    pub fn into_string(self) -> String {
+        let mut sections = Vec::new();
+        if !self.added.is_empty() {
+            let lines: Vec<_> = self.added.iter().map(|node| node.line()).collect();
+            sections.push(format!("Appeared:\n{}", lines.join("\n")));
+        }
+        if !self.changed.is_empty() {
+            let lines: Vec<_> = self
+                .changed
+                .iter()
+                .map(|(node, fields)| format!("{} (changed {})", node.line(), fields.join(", ")))
+                .collect();
+            sections.push(format!("Changed:\n{}", lines.join("\n")));
+        }
+        if !self.removed.is_empty() {
+            let lines: Vec<_> = self.removed.iter().map(|node| node.line()).collect();
+            sections.push(format!("Disappeared:\n{}", lines.join("\n")));
+        }
+        sections.join("\n\n")
+    }
+}
+
+/// Works out what's changed between the given old and new snapshots of a page.
+pub fn diff<'a>(old: &'a [Node], new: &'a [Node]) -> TreeDiff<'a> {
+    let old_nodes: Vec<_> = flatten(old).into_iter().filter(|n| n.dom_id != 0).collect();
+    let new_nodes: Vec<_> = flatten(new).into_iter().filter(|n| n.dom_id != 0).collect();
+    // If a DOM ID somehow appears twice, the first node with it is the one that counts
+    let mut old_by_id = HashMap::new();
+    for node in &old_nodes {
+        old_by_id.entry(node.dom_id).or_insert(*node);
+    }
+    let new_ids: HashSet<_> = new_nodes.iter().map(|n| n.dom_id).collect();
+    let mut diff = TreeDiff::default();
+
+    for new_node in new_nodes {
+        match old_by_id.get(&new_node.dom_id) {
+            Some(old_node) => {
+                let fields = changed_fields(old_node, new_node);
+                if !fields.is_empty() {
+                    diff.changed.push((new_node, fields));
+                }
+            }
+            None => diff.added.push(new_node),
+        }
+    }
+    for old_node in old_nodes {
+        if !new_ids.contains(&old_node.dom_id) {
+            diff.removed.push(old_node);
+        }
+    }
+
+    diff
+}
+/// Gets the names of everything about the given node that's changed between snapshots.
+fn changed_fields(old: &Node, new: &Node) -> Vec<String> {
+    let mut fields = Vec::new();
+    if old.role != new.role {
+        fields.push("role".to_string());
+    }
+    if old.name != new.name {
+        fields.push("name".to_string());
+    }
+    if old.description != new.description {
+        fields.push("description".to_string());
+    }
+    if old.value != new.value {
+        fields.push("value".to_string());
+    }
+    if old.actionable != new.actionable {
+        fields.push("actionability".to_string());
+    }
+    let mut keys: Vec<_> = old.properties.keys().chain(new.properties.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        if old.properties.get(key) != new.properties.get(key) {
+            fields.push(key.clone());
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(children: Vec<Node>) -> Vec<Node> {
+        vec![Node::test(1, "form", "Form").with_children(children)]
+    }
+
+    #[test]
+    fn identical_trees_have_no_diff() {
+        let old = form(vec![Node::test(2, "textbox", "Name").with_value("Ann")]);
+        let new = old.clone();
+        assert!(diff(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn finds_added_removed_and_changed_nodes() {
+        let old = form(vec![
+            Node::test(2, "textbox", "Name").with_value("Ann"),
+            Node::test(3, "textbox", "Old"),
+        ]);
+        let new = form(vec![
+            Node::test(2, "textbox", "Name").with_value("Bob"),
+            Node::test(4, "textbox", "New"),
+        ]);
+        let diff = diff(&old, &new);
+
+        let added: Vec<_> = diff.added.iter().map(|n| n.dom_id).collect();
+        let removed: Vec<_> = diff.removed.iter().map(|n| n.dom_id).collect();
+        assert_eq!(added, [4]);
+        assert_eq!(removed, [3]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].0.dom_id, 2);
+        assert_eq!(diff.changed[0].0.value.as_deref(), Some("Bob"));
+        assert_eq!(diff.changed[0].1, ["value"]);
+        assert_eq!(diff.len(), 3);
+    }
+
+    #[test]
+    fn changes_to_children_dont_change_parents() {
+        let old = form(vec![Node::test(2, "textbox", "Name")]);
+        let new = form(vec![Node::test(2, "textbox", "Full name")]);
+        let diff = diff(&old, &new);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].0.dom_id, 2);
+        assert_eq!(diff.changed[0].1, ["name"]);
+    }
+
+    #[test]
+    fn lists_changed_properties_by_name() {
+        let old = vec![Node::test(1, "checkbox", "Agree")];
+        let new = vec![Node::test(1, "checkbox", "Agree").with_property("checked", "true")];
+        assert_eq!(diff(&old, &new).changed[0].1, ["checked"]);
+    }
+
+    #[test]
+    fn ignores_nodes_without_dom_ids() {
+        let old = vec![Node::test(0, "StaticText", "Some text").unactionable()];
+        let new = vec![Node::test(0, "StaticText", "Other text").unactionable()];
+        assert!(diff(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn uses_the_first_node_when_ids_repeat() {
+        let old = vec![
+            Node::test(1, "textbox", "First"),
+            Node::test(1, "textbox", "Second"),
+        ];
+        let new = vec![Node::test(1, "textbox", "First")];
+        assert!(diff(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn describes_each_section_on_its_own_lines() {
+        let old = vec![
+            Node::test(1, "textbox", "Name").with_value("Ann"),
+            Node::test(2, "textbox", "Gone"),
+        ];
+        let new = vec![
+            Node::test(1, "textbox", "Name").with_value("Bob"),
+            Node::test(3, "textbox", "Here"),
+        ];
+        assert_eq!(
+            diff(&old, &new).into_string(),
+            "Appeared:\n- [3] \"Here\" (textbox)\n\n\
+             Changed:\n- [1] \"Name\" (textbox) with value Bob (changed value)\n\n\
+             Disappeared:\n- [2] \"Gone\" (textbox)"
+        );
+    }
+}
@@ -0,0 +1,644 @@
+//! A grammar for simple commands that can be run locally, without a round trip to the LLM.
+//! This covers the most common things a user might say (e.g. "scroll down", "click Sign in"),
+//! and anything that doesn't match falls back to the LLM.
+
+use crate::navigation::{self, NavCommand};
+use crate::node::{flatten, Node};
+
+/// The minimum similarity between a spoken element name and an element's actual name for
+/// them to be considered a match.
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// Words that are just politeness, and that can be stripped from the start of a command.
+const POLITE_PREFIXES: &[&str] = &["please", "can you", "could you", "would you"];
+/// Words that can be stripped from the start of an element name.
+const ARTICLES: &[&str] = &["on", "the", "a", "an"];
+/// Words at the start of a reply picking from a list of options that don't say which.
+const CHOICE_PREFIXES: &[&str] = &[
+    "i mean", "i meant", "i want", "pick", "choose", "select", "use", "number", "option",
+];
+/// Words at the end of a request to undo some steps that say what's being counted.
+const STEP_WORDS: &[&str] = &[
+    "steps", "step", "things", "thing", "actions", "action", "changes", "change",
+];
+/// Ways of saying each position in a list, in order.
+const ORDINALS: &[&[&str]] = &[
+    &["first", "1st", "one", "1"],
+    &["second", "2nd", "two", "2"],
+    &["third", "3rd", "three", "3"],
+    &["fourth", "4th", "four", "4"],
+    &["fifth", "5th", "five", "5"],
+    &["sixth", "6th", "six", "6"],
+    &["seventh", "7th", "seven", "7"],
+    &["eighth", "8th", "eight", "8"],
+    &["ninth", "9th", "nine", "9"],
+    &["tenth", "10th", "ten", "10"],
+];
+/// Words at the end of an element name that say what role the element has, in order of
+/// precedence (longer ones have to come first so they're matched first).
+const ROLE_WORDS: &[(&str, &[&str])] = &[
+    ("radio button", &["radio"]),
+    ("text box", &["textbox", "searchbox", "combobox"]),
+    ("text field", &["textbox", "searchbox", "combobox"]),
+    ("search box", &["searchbox", "textbox", "combobox"]),
+    ("search bar", &["searchbox", "textbox", "combobox"]),
+    ("search field", &["searchbox", "textbox", "combobox"]),
+    ("button", &["button"]),
+    ("link", &["link"]),
+    ("checkbox", &["checkbox"]),
+    ("check box", &["checkbox"]),
+    ("textbox", &["textbox", "searchbox", "combobox"]),
+    ("box", &["textbox", "searchbox", "combobox"]),
+    ("field", &["textbox", "searchbox", "combobox"]),
+    ("input", &["textbox", "searchbox", "combobox"]),
+    ("dropdown", &["combobox", "listbox", "button"]),
+    ("menu", &["menu", "menuitem", "button"]),
+    ("tab", &["tab"]),
+    ("option", &["option", "menuitem"]),
+];
+
+/// A command that can be run without the LLM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalCommand {
+    /// Scroll the page.
+    Scroll(ScrollDirection),
+    /// Go back a page.
+    Back,
+    /// Go forward a page.
+    Forward,
+    /// Reload the page.
+    Reload,
+    /// Click the element with the given name.
+    Click(Target),
+    /// Press the given key on whatever element is focused.
+    Press(Key),
+    /// Focus the element with the given name.
+    Focus(Target),
+    /// Move around the page like a screen reader would.
+    Navigate(NavCommand),
+    /// Forget what the user has been doing, so earlier commands aren't taken into account.
+    ResetConversation,
+    /// Undo the given number of the most recent steps.
+    Undo(usize),
+}
+
+/// A direction to scroll the page in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrollDirection {
+    Up,
+    Down,
+    Top,
+    Bottom,
+}
+
+/// An element the user has referred to by name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    /// The name the user gave the element (which may be empty if they only gave its role,
+    /// e.g. "the search box").
+    pub name: String,
+    /// The roles the element might have, if the user said (e.g. "the Sign in *button*").
+    /// If this is empty, the element could have any role.
+    pub roles: Vec<&'static str>,
+}
+
+/// A key the user can press.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Enter,
+    Escape,
+    Tab,
+    Space,
+    Backspace,
+    Delete,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+}
+impl Key {
+    /// Gets the key from what the user called it, if it's one we know.
+    fn from_spoken(spoken: &str) -> Option<Self> {
+        let key = match spoken {
+            "enter" | "return" => Self::Enter,
+            "escape" | "esc" => Self::Escape,
+            "tab" => Self::Tab,
+            "space" | "spacebar" | "space bar" => Self::Space,
+            "backspace" | "back space" => Self::Backspace,
+            "delete" => Self::Delete,
+            "up" | "up arrow" | "arrow up" => Self::ArrowUp,
+            "down" | "down arrow" | "arrow down" => Self::ArrowDown,
+            "left" | "left arrow" | "arrow left" => Self::ArrowLeft,
+            "right" | "right arrow" | "arrow right" => Self::ArrowRight,
+            "page up" => Self::PageUp,
+            "page down" => Self::PageDown,
+            "home" => Self::Home,
+            "end" => Self::End,
+            _ => return None,
+        };
+        Some(key)
+    }
+    /// The value of the key, as in `KeyboardEvent.key`.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::Enter => "Enter",
+            Self::Escape => "Escape",
+            Self::Tab => "Tab",
+            Self::Space => " ",
+            Self::Backspace => "Backspace",
+            Self::Delete => "Delete",
+            Self::ArrowUp => "ArrowUp",
+            Self::ArrowDown => "ArrowDown",
+            Self::ArrowLeft => "ArrowLeft",
+            Self::ArrowRight => "ArrowRight",
+            Self::PageUp => "PageUp",
+            Self::PageDown => "PageDown",
+            Self::Home => "Home",
+            Self::End => "End",
+        }
+    }
+    /// The physical key, as in `KeyboardEvent.code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Space => "Space",
+            other => other.key(),
+        }
+    }
+    /// The legacy key code, which some sites still check.
+    pub fn key_code(&self) -> u32 {
+        match self {
+            Self::Enter => 13,
+            Self::Escape => 27,
+            Self::Tab => 9,
+            Self::Space => 32,
+            Self::Backspace => 8,
+            Self::Delete => 46,
+            Self::ArrowUp => 38,
+            Self::ArrowDown => 40,
+            Self::ArrowLeft => 37,
+            Self::ArrowRight => 39,
+            Self::PageUp => 33,
+            Self::PageDown => 34,
+            Self::Home => 36,
+            Self::End => 35,
+        }
+    }
+    /// The text the key inserts, if any.
+    pub fn text(&self) -> Option<&'static str> {
+        match self {
+            Self::Enter => Some("\r"),
+            Self::Space => Some(" "),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the given transcript into a command that can be run locally, if it matches the
+/// grammar.
+pub fn parse(transcript: &str) -> Option<LocalCommand> {
+    let normalized = normalize(transcript);
+    let mut command = normalized.as_str();
+    for prefix in POLITE_PREFIXES {
+        command = strip_word_prefix(command, prefix).unwrap_or(command);
+    }
+    let command = command.strip_suffix(" please").unwrap_or(command).trim();
+
+    let parsed = match command {
+        "scroll down" | "page down" | "scroll" => LocalCommand::Scroll(ScrollDirection::Down),
+        "scroll up" | "page up" => LocalCommand::Scroll(ScrollDirection::Up),
+        "scroll to the top" | "scroll to top" | "go to the top" | "go to top" | "top of page"
+        | "top of the page" => LocalCommand::Scroll(ScrollDirection::Top),
+        "scroll to the bottom"
+        | "scroll to bottom"
+        | "go to the bottom"
+        | "go to bottom"
+        | "bottom of page"
+        | "bottom of the page" => LocalCommand::Scroll(ScrollDirection::Bottom),
+        "go back" | "back" | "go back a page" | "previous page" => LocalCommand::Back,
+        "go forward" | "forward" | "go forward a page" => LocalCommand::Forward,
+        "reload" | "refresh" | "reload the page" | "refresh the page" | "reload page"
+        | "refresh page" => LocalCommand::Reload,
+        "reset"
+        | "start over"
+        | "start again"
+        | "new conversation"
+        | "start a new conversation"
+        | "reset the conversation"
+        | "clear the conversation"
+        | "forget everything" => LocalCommand::ResetConversation,
+        _ => {
+            if let Some(count) = parse_undo(command) {
+                LocalCommand::Undo(count)
+            } else if let Some(nav_command) = navigation::parse(command) {
+                LocalCommand::Navigate(nav_command)
+            } else if let Some(rest) =
+                strip_word_prefix(command, "press").or_else(|| strip_word_prefix(command, "hit"))
+            {
+                // "press the enter key" is a key, "press the submit button" is a click
+                let key_name = strip_articles(rest);
+                let key_name = key_name.strip_suffix(" key").unwrap_or(key_name);
+                match Key::from_spoken(key_name) {
+                    Some(key) => LocalCommand::Press(key),
+                    None => LocalCommand::Click(parse_target(rest)?),
+                }
+            } else if let Some(rest) =
+                strip_word_prefix(command, "click").or_else(|| strip_word_prefix(command, "tap"))
+            {
+                LocalCommand::Click(parse_target(rest)?)
+            } else if let Some(rest) = strip_word_prefix(command, "focus") {
+                LocalCommand::Focus(parse_target(rest)?)
+            } else {
+                return None;
+            }
+        }
+    };
+    Some(parsed)
+}
+
+/// Parses a request to undo some steps (e.g. "undo", "undo that", "undo the last three
+/// steps"), returning how many.
+fn parse_undo(command: &str) -> Option<usize> {
+    let rest = strip_articles(strip_word_prefix(command, "undo")?);
+    let rest = strip_word_prefix(rest, "last").unwrap_or(rest);
+    let rest = STEP_WORDS
+        .iter()
+        .find_map(|word| {
+            rest.strip_suffix(word)
+                .map(|r| r.trim_end())
+                .filter(|r| r.is_empty() || rest.ends_with(&format!(" {word}")))
+        })
+        .unwrap_or(rest);
+    match rest {
+        "" | "that" | "it" | "this" => Some(1),
+        _ => ORDINALS
+            .iter()
+            .position(|words| words.contains(&rest))
+            .map(|index| index + 1),
+    }
+}
+
+/// Parses a reply picking one of the given options, which can be by position (e.g. "the second
+/// one", "number three", "the last one") or by name. This returns the index of the option
+/// picked, or `None` if it's unclear.
+pub fn parse_choice(reply: &str, options: &[String]) -> Option<usize> {
+    let reply = normalize(reply);
+    let mut reply = reply.strip_suffix(" please").unwrap_or(&reply);
+    while let Some(rest) = CHOICE_PREFIXES
+        .iter()
+        .chain(ARTICLES)
+        .find_map(|prefix| strip_word_prefix(reply, prefix))
+    {
+        reply = rest;
+    }
+    // "The second one", but not just "one"
+    let position = match reply.strip_suffix(" one") {
+        Some(rest) if !rest.is_empty() => rest,
+        _ => reply,
+    };
+
+    if position == "last" {
+        return options.len().checked_sub(1);
+    }
+    if let Some(index) = ORDINALS.iter().position(|words| words.contains(&position)) {
+        return (index < options.len()).then_some(index);
+    }
+
+    // Otherwise the user must have said (part of) the name of the one they want
+    let mut best: Option<(usize, f64)> = None;
+    let mut tied = false;
+    for (index, option) in options.iter().enumerate() {
+        let score = similarity(reply, option);
+        match best {
+            Some((_, best_score)) if (score - best_score).abs() < f64::EPSILON => tied = true,
+            Some((_, best_score)) if score < best_score => {}
+            _ => {
+                best = Some((index, score));
+                tied = false;
+            }
+        }
+    }
+    best.filter(|(_, score)| *score >= MIN_SIMILARITY && !tied)
+        .map(|(index, _)| index)
+}
+
+/// Parses a reply to a yes/no question, returning `None` if it's neither.
+pub fn parse_yes_no(reply: &str) -> Option<bool> {
+    let reply = normalize(reply);
+    let reply = reply.strip_suffix(" please").unwrap_or(&reply);
+    match reply {
+        "yes" | "yeah" | "yep" | "yup" | "sure" | "ok" | "okay" | "go ahead" | "do it"
+        | "confirm" | "yes do it" | "yes go ahead" | "correct" | "continue" => Some(true),
+        "no" | "nope" | "nah" | "cancel" | "stop" | "don't" | "do not" | "no thanks"
+        | "don't do it" | "abort" => Some(false),
+        _ => None,
+    }
+}
+
+/// Finds the actionable node in the given tree that best matches the given target, if any
+/// match well enough. Names are matched fuzzily, so small transcription errors are fine.
+pub fn resolve_target<'a>(target: &Target, tree: &'a [Node]) -> Option<&'a Node> {
+    let mut best: Option<(&Node, f64)> = None;
+    for node in flatten(tree).into_iter().filter(|n| n.actionable) {
+        let role = node.role.as_deref().unwrap_or("");
+        let role_matches = target.roles.contains(&role);
+        if !target.roles.is_empty() && !role_matches {
+            continue;
+        }
+
+        let score = if target.name.is_empty() {
+            // The user only gave a role, so take the first element with it
+            if role_matches {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            let name_score = node
+                .name
+                .as_deref()
+                .map(|name| similarity(&target.name, name))
+                .unwrap_or(0.0);
+            // Sometimes the user will refer to something by its role (e.g. "focus search"
+            // for a nameless search box), which is a slightly weaker match
+            let role_score = similarity(&target.name, role) * 0.8;
+            name_score.max(role_score)
+        };
+
+        if score >= MIN_SIMILARITY && best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((node, score));
+        }
+    }
+
+    best.map(|(node, _)| node)
+}
+
+/// Parses the name and role of an element from what the user called it.
+fn parse_target(spoken: &str) -> Option<Target> {
+    let mut name = strip_articles(spoken);
+    let mut roles = Vec::new();
+    for (word, word_roles) in ROLE_WORDS {
+        if name == *word {
+            name = "";
+        } else if let Some(rest) = name.strip_suffix(&format!(" {word}")) {
+            name = rest.trim();
+        } else {
+            continue;
+        }
+        roles = word_roles.to_vec();
+        break;
+    }
+
+    if name.is_empty() && roles.is_empty() {
+        None
+    } else {
+        Some(Target {
+            name: name.to_string(),
+            roles,
+        })
+    }
+}
+
+/// Lowercases the given text, strips out punctuation, and collapses whitespace.
+pub fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '\'' {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Strips the given word (or words) from the start of the given text, if it's there as a
+/// whole word.
+pub(crate) fn strip_word_prefix<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = text.strip_prefix(prefix)?;
+    if rest.is_empty() {
+        Some(rest)
+    } else {
+        rest.strip_prefix(' ')
+    }
+}
+
+/// Strips any articles (e.g. "the") from the start of the given text.
+fn strip_articles(mut text: &str) -> &str {
+    while let Some(rest) = ARTICLES
+        .iter()
+        .find_map(|article| strip_word_prefix(text, article))
+    {
+        text = rest;
+    }
+    text
+}
+
+/// Computes how similar the given spoken name is to the given actual name, from 0 (not at
+/// all) to 1 (identical). This is forgiving of small transcription errors, and of the user
+/// only giving part of a long name.
+pub fn similarity(spoken: &str, actual: &str) -> f64 {
+    let spoken = normalize(spoken);
+    let actual = normalize(actual);
+    if spoken.is_empty() || actual.is_empty() {
+        return 0.0;
+    }
+    if spoken == actual {
+        return 1.0;
+    }
+
+    // Character-level similarity catches transcription errors in short names
+    let char_score =
+        1.0 - levenshtein(&spoken, &actual) as f64 / spoken.len().max(actual.len()) as f64;
+
+    // Word-level similarity catches the user only giving some of the words
+    let spoken_words: Vec<_> = spoken.split(' ').collect();
+    let actual_words: Vec<_> = actual.split(' ').collect();
+    let words_match = |a: &str, b: &str| a == b || (a.len() >= 4 && levenshtein(a, b) <= 1);
+    let num_common = spoken_words
+        .iter()
+        .filter(|s| actual_words.iter().any(|a| words_match(s, a)))
+        .count();
+    let jaccard =
+        num_common as f64 / (spoken_words.len() + actual_words.len() - num_common).max(1) as f64;
+    // If everything the user said is in the name, that's a good match, even if the name is
+    // much longer
+    let containment_score = if num_common == spoken_words.len() {
+        0.75 + 0.25 * jaccard
+    } else {
+        0.0
+    };
+
+    char_score.max(jaccard).max(containment_score)
+}
+
+/// Computes the edit distance between two strings.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        curr[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            curr[j] = (prev[j] + 1).min(curr[j - 1] + 1).min(prev[j - 1] + cost);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::navigation::NavCategory;
+
+    fn target(name: &str, roles: &[&'static str]) -> Target {
+        Target {
+            name: name.to_string(),
+            roles: roles.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_fixed_commands() {
+        assert_eq!(
+            parse("Please scroll down."),
+            Some(LocalCommand::Scroll(ScrollDirection::Down))
+        );
+        assert_eq!(
+            parse("go to the top please"),
+            Some(LocalCommand::Scroll(ScrollDirection::Top))
+        );
+        assert_eq!(parse("Could you go back?"), Some(LocalCommand::Back));
+        assert_eq!(parse("Refresh the page"), Some(LocalCommand::Reload));
+        assert_eq!(parse("Start over"), Some(LocalCommand::ResetConversation));
+    }
+
+    #[test]
+    fn parses_commands_on_elements() {
+        assert_eq!(
+            parse("Click the Sign in button"),
+            Some(LocalCommand::Click(target("sign in", &["button"])))
+        );
+        assert_eq!(
+            parse("tap on Pricing"),
+            Some(LocalCommand::Click(target("pricing", &[])))
+        );
+        assert_eq!(
+            parse("focus the search box"),
+            Some(LocalCommand::Focus(target(
+                "",
+                &["searchbox", "textbox", "combobox"]
+            )))
+        );
+        // Nothing to act on
+        assert_eq!(parse("click"), None);
+    }
+
+    #[test]
+    fn tells_keys_from_buttons() {
+        assert_eq!(
+            parse("press the enter key"),
+            Some(LocalCommand::Press(Key::Enter))
+        );
+        assert_eq!(parse("hit escape"), Some(LocalCommand::Press(Key::Escape)));
+        assert_eq!(
+            parse("press the submit button"),
+            Some(LocalCommand::Click(target("submit", &["button"])))
+        );
+    }
+
+    #[test]
+    fn parses_navigation_and_undo() {
+        assert_eq!(
+            parse("next heading"),
+            Some(LocalCommand::Navigate(NavCommand::Next(
+                NavCategory::Heading
+            )))
+        );
+        assert_eq!(
+            parse("Undo the last three steps"),
+            Some(LocalCommand::Undo(3))
+        );
+    }
+
+    #[test]
+    fn leaves_everything_else_to_the_llm() {
+        assert_eq!(parse("what's the weather like"), None);
+        assert_eq!(parse("buy the blue shirt"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn parses_how_many_steps_to_undo() {
+        assert_eq!(parse_undo("undo"), Some(1));
+        assert_eq!(parse_undo("undo that"), Some(1));
+        assert_eq!(parse_undo("undo the last step"), Some(1));
+        assert_eq!(parse_undo("undo the last three steps"), Some(3));
+        assert_eq!(parse_undo("undo last 2 changes"), Some(2));
+        // Step words only count as whole words
+        assert_eq!(parse_undo("undo missteps"), None);
+        assert_eq!(parse_undo("undo everything"), None);
+        assert_eq!(parse_undo("redo"), None);
+    }
+
+    #[test]
+    fn parses_choices_by_position() {
+        let options = ["Small", "Medium", "Large"].map(String::from);
+        assert_eq!(parse_choice("the second one", &options), Some(1));
+        assert_eq!(parse_choice("Number three, please", &options), Some(2));
+        assert_eq!(parse_choice("the last one", &options), Some(2));
+        assert_eq!(parse_choice("one", &options), Some(0));
+        assert_eq!(parse_choice("the fourth one", &options), None);
+        assert_eq!(parse_choice("the last one", &[]), None);
+    }
+
+    #[test]
+    fn parses_choices_by_name() {
+        let options = ["Small", "Medium", "Large"].map(String::from);
+        assert_eq!(parse_choice("medium please", &options), Some(1));
+        assert_eq!(parse_choice("I meant large", &options), Some(2));
+        assert_eq!(parse_choice("purple", &options), None);
+
+        // Equally good matches are unclear
+        let options = ["Blue shirt", "Blue shorts"].map(String::from);
+        assert_eq!(parse_choice("blue", &options), None);
+        assert_eq!(parse_choice("the blue shorts", &options), Some(1));
+    }
+
+    #[test]
+    fn parses_yes_and_no() {
+        assert_eq!(parse_yes_no("Yes, go ahead."), Some(true));
+        assert_eq!(parse_yes_no("OK please"), Some(true));
+        assert_eq!(parse_yes_no("No thanks"), Some(false));
+        assert_eq!(parse_yes_no("Cancel"), Some(false));
+        assert_eq!(parse_yes_no("maybe"), None);
+        assert_eq!(parse_yes_no("yes and no"), None);
+    }
+
+    #[test]
+    fn similarity_ignores_case_and_punctuation() {
+        assert_eq!(similarity("sign in", "Sign in!"), 1.0);
+        assert_eq!(similarity("", "Sign in"), 0.0);
+        assert_eq!(similarity("sign in", " "), 0.0);
+    }
+
+    #[test]
+    fn similarity_forgives_transcription_errors_and_partial_names() {
+        assert!(similarity("sing in", "Sign in") >= MIN_SIMILARITY);
+        assert!(similarity("subscribe", "Subscrib") >= MIN_SIMILARITY);
+        assert!(similarity("cart", "Shopping cart") >= 0.75);
+        assert!(similarity("help", "Checkout") < MIN_SIMILARITY);
+        // Giving the whole name is better than giving some of it
+        assert!(similarity("shopping cart", "Shopping cart") > similarity("cart", "Shopping cart"));
+    }
+}
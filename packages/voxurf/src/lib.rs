@@ -1,3 +1,12 @@
-pub fn get_message() -> &'static str {
-    "Hello, world!"
-}
+pub mod diff;
+pub mod grammar;
+pub mod locator;
+pub mod navigation;
+mod node;
+pub mod redact;
+pub mod risk;
+pub mod undo;
+pub mod validate;
+pub mod verify;
+
+pub use node::{flatten, Node};
@@ -0,0 +1,397 @@
+//! Locators for elements that survive reloads and changes to the page, unlike DOM IDs. A
+//! locator describes an element the way a person would (e.g. "the Archive button in the
+//! toolbar"), and can be resolved against a fresh tree to find the element again, with a
+//! score of how confident that match is.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::grammar::similarity;
+use crate::navigation::LANDMARK_ROLES;
+use crate::node::Node;
+
+/// The longest the text hint can be when describing an element, in characters, before it's
+/// cut short.
+const MAX_DESCRIBED_TEXT_LEN: usize = 60;
+
+/// The minimum confidence a match should have to be acted on without asking the LLM.
+pub const MIN_CONFIDENCE: f64 = 0.6;
+
+/// How much the name counts towards a match.
+const NAME_WEIGHT: f64 = 0.55;
+/// How much the landmark path counts towards a match.
+const LANDMARK_WEIGHT: f64 = 0.2;
+/// How much the text hint counts towards a match, if there is one.
+const TEXT_WEIGHT: f64 = 0.15;
+/// How much the attribute hints count towards a match, if there are any.
+const ATTRIBUTE_WEIGHT: f64 = 0.1;
+/// What the confidence of a match is multiplied by if another element matches just as well,
+/// since we can't be sure which one was meant.
+const AMBIGUITY_PENALTY: f64 = 0.75;
+/// Properties of a node that don't change as the user interacts with it, and so can be used
+/// as hints to find it again.
+const STABLE_PROPERTIES: &[&str] = &[
+    "autocomplete",
+    "haspopup",
+    "level",
+    "multiline",
+    "multiselectable",
+    "readonly",
+    "required",
+    "roledescription",
+    "keyshortcuts",
+];
+
+/// A landmark an element is inside.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Landmark {
+    pub role: String,
+    pub name: Option<String>,
+}
+
+/// A description of an element that can be used to find it again in a different tree of the
+/// same page (e.g. after it's been reloaded).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Locator {
+    /// The element's role, which must match exactly.
+    pub role: Option<String>,
+    /// The element's accessible name.
+    pub name: Option<String>,
+    /// The landmarks the element is inside, outermost first.
+    pub landmarks: Vec<Landmark>,
+    /// Text that describes the element, or the nearest text before it, which helps tell
+    /// apart elements with the same name (e.g. the Reply buttons of different comments).
+    pub text: Option<String>,
+    /// Properties of the element that don't change as the user interacts with it.
+    pub attributes: HashMap<String, String>,
+}
+
+/// An element found by resolving a [`Locator`].
+#[derive(Clone, Copy, Debug)]
+pub struct Match<'a> {
+    pub node: &'a Node,
+    /// How confident we are that this is the element the locator describes, from 0 to 1.
+    pub confidence: f64,
+}
+
+/// An element in a tree along with the context a locator needs.
+struct Candidate<'a> {
+    node: &'a Node,
+    landmarks: Vec<Landmark>,
+    text: Option<String>,
+}
+
+impl Locator {
+    /// Creates a locator for the node with the given DOM ID in the given tree, if there is
+    /// one.
+    pub fn for_node(tree: &[Node], dom_id: u32) -> Option<Self> {
+        let candidate = candidates(tree)
+            .into_iter()
+            .find(|c| c.node.dom_id == dom_id)?;
+        Some(Self {
+            role: candidate.node.role.clone(),
+            name: candidate.node.name.clone().filter(|n| !n.is_empty()),
+            landmarks: candidate.landmarks,
+            text: candidate.text,
+            attributes: candidate
+                .node
+                .properties
+                .iter()
+                .filter(|(key, _)| STABLE_PROPERTIES.contains(&key.as_str()))
+                .map(|(key, val)| (key.clone(), val.clone()))
+                .collect(),
+        })
+    }
+
+    /// Finds the actionable node in the given tree that best matches this locator, if any
+    /// match at all. If several match equally well, the first is returned, with a lower
+    /// confidence.
+    pub fn resolve<'a>(&self, tree: &'a [Node]) -> Option<Match<'a>> {
+        let mut best: Option<Match<'a>> = None;
+        let mut tied = false;
+        for candidate in candidates(tree) {
+            if !candidate.node.actionable {
+                continue;
+            }
+            let score = self.score(&candidate);
+            if score <= 0.0 {
+                continue;
+            }
+            match best {
+                Some(b) if (score - b.confidence).abs() < f64::EPSILON => tied = true,
+                Some(b) if score < b.confidence => {}
+                _ => {
+                    best = Some(Match {
+                        node: candidate.node,
+                        confidence: score,
+                    });
+                    tied = false;
+                }
+            }
+        }
+
+        best.map(|b| Match {
+            confidence: if tied {
+                b.confidence * AMBIGUITY_PENALTY
+            } else {
+                b.confidence
+            },
+            ..b
+        })
+    }
+
+    /// Describes the element this locator is for briefly, in a way that can be read out to
+    /// tell it apart from similar elements (e.g. `"Delete" button, near "Invoice 42"`).
+    pub fn describe(&self) -> String {
+        let mut description = match (&self.name, &self.role) {
+            (Some(name), Some(role)) => format!("\"{name}\" {role}"),
+            (Some(name), None) => format!("\"{name}\""),
+            (None, Some(role)) => format!("unnamed {role}"),
+            (None, None) => "unnamed element".to_string(),
+        };
+        if let Some(text) = &self.text {
+            let text = if text.chars().count() > MAX_DESCRIBED_TEXT_LEN {
+                let cut: String = text.chars().take(MAX_DESCRIBED_TEXT_LEN).collect();
+                // Don't stop halfway through a word
+                let cut = cut
+                    .rsplit_once(' ')
+                    .map_or(cut.as_str(), |(start, _)| start);
+                format!("{cut}...")
+            } else {
+                text.clone()
+            };
+            description.push_str(&format!(", near \"{text}\""));
+        }
+        if let Some(landmark) = self.landmarks.last() {
+            description.push_str(&format!(
+                ", in the {}",
+                landmark.name.as_deref().unwrap_or(&landmark.role)
+            ));
+        }
+        description
+    }
+
+    /// Scores how well the given candidate matches this locator, from 0 to 1.
+    fn score(&self, candidate: &Candidate) -> f64 {
+        if self.role.is_some() && self.role != candidate.node.role {
+            return 0.0;
+        }
+
+        let mut total = NAME_WEIGHT
+            * text_similarity(
+                self.name.as_deref(),
+                candidate.node.name.as_deref().filter(|n| !n.is_empty()),
+            );
+        let mut weight = NAME_WEIGHT;
+
+        total += LANDMARK_WEIGHT * landmark_similarity(&self.landmarks, &candidate.landmarks);
+        weight += LANDMARK_WEIGHT;
+
+        if self.text.is_some() {
+            total += TEXT_WEIGHT * text_similarity(self.text.as_deref(), candidate.text.as_deref());
+            weight += TEXT_WEIGHT;
+        }
+
+        if !self.attributes.is_empty() {
+            let num_matching = self
+                .attributes
+                .iter()
+                .filter(|(key, val)| candidate.node.properties.get(*key) == Some(val))
+                .count();
+            total += ATTRIBUTE_WEIGHT * num_matching as f64 / self.attributes.len() as f64;
+            weight += ATTRIBUTE_WEIGHT;
+        }
+
+        total / weight
+    }
+}
+
+/// Computes how similar two optional pieces of text are, where two missing ones are
+/// identical.
+fn text_similarity(expected: Option<&str>, actual: Option<&str>) -> f64 {
+    match (expected, actual) {
+        (None, None) => 1.0,
+        (Some(expected), Some(actual)) => similarity(expected, actual),
+        _ => 0.0,
+    }
+}
+
+/// Computes how similar two landmark paths are, from 0 to 1, by how many of the expected
+/// landmarks are in the actual path, in order.
+fn landmark_similarity(expected: &[Landmark], actual: &[Landmark]) -> f64 {
+    if expected.is_empty() && actual.is_empty() {
+        return 1.0;
+    }
+    let mut remaining = actual.iter();
+    let num_matching = expected
+        .iter()
+        .filter(|e| remaining.any(|a| a == *e))
+        .count();
+    num_matching as f64 / expected.len().max(actual.len()) as f64
+}
+
+/// Gets every node in the given tree, in document order, along with its context.
+fn candidates(tree: &[Node]) -> Vec<Candidate<'_>> {
+    let mut out = Vec::new();
+    collect_candidates(tree, &mut Vec::new(), &mut None, &mut out);
+    out
+}
+
+fn collect_candidates<'a>(
+    nodes: &'a [Node],
+    landmarks: &mut Vec<Landmark>,
+    last_text: &mut Option<String>,
+    out: &mut Vec<Candidate<'a>>,
+) {
+    for node in nodes {
+        out.push(Candidate {
+            node,
+            landmarks: landmarks.clone(),
+            text: node
+                .description
+                .clone()
+                .filter(|d| !d.is_empty())
+                .or_else(|| last_text.clone()),
+        });
+        if node.is_static_text() || node.role.as_deref() == Some("heading") {
+            if let Some(name) = node.name.as_ref().filter(|n| !n.trim().is_empty()) {
+                *last_text = Some(name.trim().to_string());
+            }
+        }
+
+        let role = node.role.as_deref().unwrap_or("");
+        let is_landmark = LANDMARK_ROLES.contains(&role);
+        if is_landmark {
+            landmarks.push(Landmark {
+                role: role.to_string(),
+                name: node.name.clone().filter(|n| !n.is_empty()),
+            });
+        }
+        collect_candidates(&node.children, landmarks, last_text, out);
+        if is_landmark {
+            landmarks.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comments() -> Vec<Node> {
+        vec![
+            Node::test(1, "navigation", "Site").with_children(vec![Node::test(2, "link", "Home")]),
+            Node::test(3, "main", "").with_children(vec![
+                Node::test(4, "StaticText", "Great post!").unactionable(),
+                Node::test(5, "button", "Reply"),
+                Node::test(6, "StaticText", "I disagree.").unactionable(),
+                Node::test(7, "button", "Reply"),
+                Node::test(8, "button", "Archive"),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn finds_the_node_it_was_made_from() {
+        let tree = comments();
+        for dom_id in [2, 5, 7, 8] {
+            let locator = Locator::for_node(&tree, dom_id).unwrap();
+            let found = locator.resolve(&tree).unwrap();
+            assert_eq!(found.node.dom_id, dom_id);
+            assert_eq!(found.confidence, 1.0);
+        }
+        assert!(Locator::for_node(&tree, 99).is_none());
+    }
+
+    #[test]
+    fn finds_nodes_again_after_the_page_changes() {
+        let locator = Locator::for_node(&comments(), 7).unwrap();
+        let mut changed = comments();
+        changed[1]
+            .children
+            .insert(0, Node::test(100, "button", "Like"));
+        let found = locator.resolve(&changed).unwrap();
+        assert_eq!(found.node.dom_id, 7);
+        assert_eq!(found.confidence, 1.0);
+    }
+
+    #[test]
+    fn finds_nodes_by_name_alone() {
+        let tree = comments();
+        let locator = Locator {
+            name: Some("archive".to_string()),
+            ..Default::default()
+        };
+        let found = locator.resolve(&tree).unwrap();
+        assert_eq!(found.node.dom_id, 8);
+        // Not knowing which landmark it's in costs some confidence, but not too much
+        assert!(found.confidence < 1.0);
+        assert!(found.confidence >= MIN_CONFIDENCE);
+    }
+
+    #[test]
+    fn roles_must_match_exactly() {
+        let locator = Locator {
+            role: Some("link".to_string()),
+            name: Some("Archive".to_string()),
+            ..Default::default()
+        };
+        // The Archive button is the wrong role, so only the Home link is left
+        let tree = comments();
+        let found = locator.resolve(&tree).unwrap();
+        assert_eq!(found.node.dom_id, 2);
+        assert!(found.confidence < MIN_CONFIDENCE);
+
+        let locator = Locator {
+            role: Some("checkbox".to_string()),
+            ..locator
+        };
+        assert!(locator.resolve(&tree).is_none());
+    }
+
+    #[test]
+    fn equally_good_matches_are_penalized() {
+        let tree = comments();
+        let exact = Locator {
+            role: Some("button".to_string()),
+            name: Some("Reply".to_string()),
+            landmarks: vec![Landmark {
+                role: "main".to_string(),
+                name: None,
+            }],
+            ..Default::default()
+        };
+        let found = exact.resolve(&tree).unwrap();
+        assert_eq!(found.node.dom_id, 5);
+        assert_eq!(found.confidence, AMBIGUITY_PENALTY);
+
+        // A weaker match that's tied isn't confident enough to act on, though it would be on
+        // its own
+        let name_only = Locator {
+            name: Some("Reply".to_string()),
+            ..Default::default()
+        };
+        let found = name_only.resolve(&tree).unwrap();
+        assert_eq!(found.node.dom_id, 5);
+        assert!(found.confidence < MIN_CONFIDENCE);
+
+        let mut single = comments();
+        single[1].children.retain(|n| n.dom_id != 7);
+        let found = name_only.resolve(&single).unwrap();
+        assert!(found.confidence >= MIN_CONFIDENCE);
+    }
+
+    #[test]
+    fn text_hints_tell_apart_nodes_with_the_same_name() {
+        let tree = comments();
+        let locator = Locator {
+            role: Some("button".to_string()),
+            name: Some("Reply".to_string()),
+            text: Some("I disagree.".to_string()),
+            ..Default::default()
+        };
+        let found = locator.resolve(&tree).unwrap();
+        assert_eq!(found.node.dom_id, 7);
+    }
+}
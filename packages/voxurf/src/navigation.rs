@@ -0,0 +1,413 @@
+//! Screen-reader-style navigation over the accessibility tree, which lets the user move
+//! through a page by headings, landmarks, links, and so on, without the LLM. A virtual cursor
+//! keeps track of where the user is between commands.
+
+use crate::grammar::strip_word_prefix;
+use crate::node::{flatten, Node};
+
+/// The maximum number of items read out when listing everything in a category.
+const MAX_LISTED: usize = 20;
+
+/// Roles that count as form fields.
+const FORM_FIELD_ROLES: &[&str] = &[
+    "textbox",
+    "searchbox",
+    "combobox",
+    "checkbox",
+    "radio",
+    "listbox",
+    "slider",
+    "spinbutton",
+    "switch",
+];
+/// Roles that count as landmarks.
+pub(crate) const LANDMARK_ROLES: &[&str] = &[
+    "banner",
+    "navigation",
+    "main",
+    "contentinfo",
+    "complementary",
+    "region",
+    "search",
+    "form",
+];
+/// What users call landmarks, and the roles those names refer to.
+const LANDMARK_NAMES: &[(&str, &str)] = &[
+    ("main content", "main"),
+    ("main", "main"),
+    ("content", "main"),
+    ("navigation", "navigation"),
+    ("nav", "navigation"),
+    ("search", "search"),
+    ("header", "banner"),
+    ("banner", "banner"),
+    ("footer", "contentinfo"),
+    ("sidebar", "complementary"),
+];
+
+/// A kind of element the user can move between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NavCategory {
+    Heading,
+    Landmark,
+    Link,
+    FormField,
+    Button,
+}
+impl NavCategory {
+    /// Gets the category from what the user called it (singular or plural), if it's one we
+    /// know.
+    fn from_spoken(spoken: &str) -> Option<Self> {
+        let spoken = spoken.strip_suffix('s').unwrap_or(spoken);
+        let category = match spoken {
+            "heading" | "header" | "title" => Self::Heading,
+            "landmark" | "region" | "section" => Self::Landmark,
+            "link" => Self::Link,
+            "form field" | "field" | "input" | "form control" | "control" => Self::FormField,
+            "button" => Self::Button,
+            _ => return None,
+        };
+        Some(category)
+    }
+    /// Whether or not the given node is in this category.
+    pub fn matches(&self, node: &Node) -> bool {
+        let role = node.role.as_deref().unwrap_or("");
+        match self {
+            Self::Heading => role == "heading",
+            Self::Landmark => LANDMARK_ROLES.contains(&role),
+            Self::Link => role == "link",
+            Self::FormField => FORM_FIELD_ROLES.contains(&role),
+            Self::Button => role == "button",
+        }
+    }
+    /// What to call more than one element in this category when talking to the user.
+    pub fn plural(&self) -> &'static str {
+        match self {
+            Self::Heading => "headings",
+            Self::Landmark => "landmarks",
+            Self::Link => "links",
+            Self::FormField => "form fields",
+            Self::Button => "buttons",
+        }
+    }
+}
+
+/// A navigation command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NavCommand {
+    /// Move to the next element in the given category.
+    Next(NavCategory),
+    /// Move to the previous element in the given category.
+    Previous(NavCategory),
+    /// List all the elements in the given category.
+    List(NavCategory),
+    /// Move to the landmark with the given role (e.g. `main`).
+    GoToLandmark(&'static str),
+    /// Read the element the cursor is on.
+    ReadCurrent,
+}
+
+/// Parses the given normalized command (see [`crate::grammar::normalize`]) into a navigation
+/// command, if it is one.
+pub(crate) fn parse(command: &str) -> Option<NavCommand> {
+    if matches!(
+        command,
+        "read this" | "read current" | "read current element" | "where am i"
+    ) {
+        return Some(NavCommand::ReadCurrent);
+    }
+
+    if let Some(rest) = strip_word_prefix(command, "next") {
+        return Some(NavCommand::Next(NavCategory::from_spoken(rest)?));
+    }
+    if let Some(rest) =
+        strip_word_prefix(command, "previous").or_else(|| strip_word_prefix(command, "prev"))
+    {
+        return Some(NavCommand::Previous(NavCategory::from_spoken(rest)?));
+    }
+    if let Some(rest) = strip_word_prefix(command, "list")
+        .or_else(|| strip_word_prefix(command, "show"))
+        .or_else(|| strip_word_prefix(command, "what are the"))
+    {
+        let rest = strip_word_prefix(rest, "all").unwrap_or(rest);
+        let rest = strip_word_prefix(rest, "the").unwrap_or(rest);
+        return Some(NavCommand::List(NavCategory::from_spoken(rest)?));
+    }
+    if let Some(rest) = strip_word_prefix(command, "go to")
+        .or_else(|| strip_word_prefix(command, "jump to"))
+        .or_else(|| strip_word_prefix(command, "skip to"))
+    {
+        let rest = strip_word_prefix(rest, "the").unwrap_or(rest);
+        let (_, role) = LANDMARK_NAMES.iter().find(|(name, _)| *name == rest)?;
+        return Some(NavCommand::GoToLandmark(role));
+    }
+
+    None
+}
+
+/// A cursor that keeps track of where the user is on the page between navigation commands.
+/// This remembers the DOM ID of the node it's on, so it survives the tree being fetched
+/// again, as long as the page isn't reloaded.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualCursor {
+    dom_id: Option<u32>,
+}
+impl VirtualCursor {
+    /// Creates a new cursor at the top of the page.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Gets the node the cursor is on, if it's on one that's still in the given tree.
+    pub fn current<'a>(&self, tree: &'a [Node]) -> Option<&'a Node> {
+        let dom_id = self.dom_id?;
+        flatten(tree).into_iter().find(|n| n.dom_id == dom_id)
+    }
+    /// Moves the cursor to the next (or previous, if `forward` is `false`) node in the given
+    /// category, returning it. If the cursor isn't on anything, this starts from the top (or
+    /// bottom) of the page. If there's nothing more in that direction, the cursor stays where
+    /// it is and this returns `None`.
+    pub fn move_by<'a>(
+        &mut self,
+        tree: &'a [Node],
+        category: NavCategory,
+        forward: bool,
+    ) -> Option<&'a Node> {
+        let nodes = flatten(tree);
+        let current_idx = self
+            .dom_id
+            .and_then(|dom_id| nodes.iter().position(|n| n.dom_id == dom_id));
+        let found = if forward {
+            let start = current_idx.map_or(0, |idx| idx + 1);
+            nodes
+                .iter()
+                .skip(start)
+                .find(|n| category.matches(n))
+                .copied()
+        } else {
+            let end = current_idx.unwrap_or(nodes.len());
+            nodes
+                .iter()
+                .take(end)
+                .rev()
+                .find(|n| category.matches(n))
+                .copied()
+        };
+
+        if let Some(node) = found {
+            self.dom_id = Some(node.dom_id);
+        }
+        found
+    }
+    /// Moves the cursor to the first landmark with the given role, returning it.
+    pub fn go_to_landmark<'a>(&mut self, tree: &'a [Node], role: &str) -> Option<&'a Node> {
+        let found = flatten(tree)
+            .into_iter()
+            .find(|n| n.role.as_deref() == Some(role))?;
+        self.dom_id = Some(found.dom_id);
+        Some(found)
+    }
+}
+
+/// Lists all the nodes in the given category, in document order.
+pub fn list(tree: &[Node], category: NavCategory) -> Vec<&Node> {
+    flatten(tree)
+        .into_iter()
+        .filter(|n| category.matches(n))
+        .collect()
+}
+
+/// Describes the given node briefly, as a screen reader would announce it when moving to it
+/// (e.g. "Heading level 2, Blue shirts").
+pub fn announce(node: &Node) -> String {
+    let role = match node.role.as_deref() {
+        Some("heading") => match node.properties.get("level") {
+            Some(level) => format!("Heading level {level}"),
+            None => "Heading".to_string(),
+        },
+        Some("contentinfo") => "Footer".to_string(),
+        Some("banner") => "Header".to_string(),
+        Some("complementary") => "Sidebar".to_string(),
+        Some(role) => {
+            let mut chars = role.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        }
+        None => "Element".to_string(),
+    };
+    let mut announcement = match node.name.as_deref() {
+        Some(name) if !name.is_empty() => format!("{role}, {name}"),
+        _ => role,
+    };
+    if let Some(value) = node.value.as_deref().filter(|v| !v.is_empty()) {
+        announcement.push_str(&format!(", {value}"));
+    }
+    announcement
+}
+
+/// Reads out the given node in full, including all the text inside it.
+pub fn read(node: &Node) -> String {
+    let mut text = announce(node);
+    let name = node.name.as_deref().unwrap_or("");
+    for descendant in flatten(&node.children) {
+        if let Some(desc_name) = descendant.name.as_deref() {
+            // Names are often made up of the text inside them, so don't repeat things
+            if !desc_name.is_empty() && !name.contains(desc_name) {
+                text.push_str(". ");
+                text.push_str(desc_name);
+            }
+        }
+    }
+    text
+}
+
+/// Lists the given nodes in the given category briefly, for reading out.
+pub fn announce_list(nodes: &[&Node], category: NavCategory) -> String {
+    if nodes.is_empty() {
+        return format!("There are no {} on this page", category.plural());
+    }
+
+    let mut list = format!("{} {}: ", nodes.len(), category.plural());
+    let names: Vec<_> = nodes
+        .iter()
+        .take(MAX_LISTED)
+        .map(|n| match n.name.as_deref() {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => announce(n),
+        })
+        .collect();
+    list.push_str(&names.join(", "));
+    if nodes.len() > MAX_LISTED {
+        list.push_str(&format!(", and {} more", nodes.len() - MAX_LISTED));
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page() -> Vec<Node> {
+        vec![
+            Node::test(1, "banner", "").with_children(vec![Node::test(2, "link", "Home")]),
+            Node::test(3, "main", "").with_children(vec![
+                Node::test(4, "heading", "Shirts").with_property("level", "1"),
+                Node::test(5, "link", "Blue shirt"),
+                Node::test(6, "heading", "Trousers").with_property("level", "2"),
+                Node::test(7, "textbox", "Search").with_value("chinos"),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("next heading"),
+            Some(NavCommand::Next(NavCategory::Heading))
+        );
+        assert_eq!(
+            parse("prev form field"),
+            Some(NavCommand::Previous(NavCategory::FormField))
+        );
+        assert_eq!(
+            parse("list all the links"),
+            Some(NavCommand::List(NavCategory::Link))
+        );
+        assert_eq!(
+            parse("what are the buttons"),
+            Some(NavCommand::List(NavCategory::Button))
+        );
+        assert_eq!(
+            parse("skip to the main content"),
+            Some(NavCommand::GoToLandmark("main"))
+        );
+        assert_eq!(parse("where am i"), Some(NavCommand::ReadCurrent));
+    }
+
+    #[test]
+    fn leaves_unknown_categories_and_landmarks_unparsed() {
+        assert_eq!(parse("next picture"), None);
+        assert_eq!(parse("go to the basket"), None);
+        assert_eq!(parse("next"), None);
+    }
+
+    #[test]
+    fn moves_through_a_category_in_order() {
+        let tree = page();
+        let mut cursor = VirtualCursor::new();
+        assert!(cursor.current(&tree).is_none());
+
+        let found = cursor.move_by(&tree, NavCategory::Heading, true);
+        assert_eq!(found.map(|n| n.dom_id), Some(4));
+        let found = cursor.move_by(&tree, NavCategory::Heading, true);
+        assert_eq!(found.map(|n| n.dom_id), Some(6));
+        // Other categories carry on from wherever the cursor is
+        let found = cursor.move_by(&tree, NavCategory::Link, false);
+        assert_eq!(found.map(|n| n.dom_id), Some(5));
+        assert_eq!(cursor.current(&tree).map(|n| n.dom_id), Some(5));
+    }
+
+    #[test]
+    fn stays_put_at_either_end() {
+        let tree = page();
+        let mut cursor = VirtualCursor::new();
+
+        // With nowhere to start from, going backwards starts from the bottom
+        let found = cursor.move_by(&tree, NavCategory::Heading, false);
+        assert_eq!(found.map(|n| n.dom_id), Some(6));
+        assert!(cursor.move_by(&tree, NavCategory::Heading, true).is_none());
+        assert_eq!(cursor.current(&tree).map(|n| n.dom_id), Some(6));
+
+        cursor.move_by(&tree, NavCategory::Link, false);
+        cursor.move_by(&tree, NavCategory::Link, false);
+        assert!(cursor.move_by(&tree, NavCategory::Link, false).is_none());
+        assert_eq!(cursor.current(&tree).map(|n| n.dom_id), Some(2));
+
+        assert!(cursor.move_by(&tree, NavCategory::Button, true).is_none());
+        assert_eq!(cursor.current(&tree).map(|n| n.dom_id), Some(2));
+    }
+
+    #[test]
+    fn goes_to_landmarks() {
+        let tree = page();
+        let mut cursor = VirtualCursor::new();
+        let found = cursor.go_to_landmark(&tree, "main");
+        assert_eq!(found.map(|n| n.dom_id), Some(3));
+        // The next heading is the first one in that landmark
+        let found = cursor.move_by(&tree, NavCategory::Heading, true);
+        assert_eq!(found.map(|n| n.dom_id), Some(4));
+        assert!(cursor.go_to_landmark(&tree, "contentinfo").is_none());
+    }
+
+    #[test]
+    fn announces_lists() {
+        let tree = page();
+        assert_eq!(
+            announce_list(&list(&tree, NavCategory::Heading), NavCategory::Heading),
+            "2 headings: Shirts, Trousers"
+        );
+        // Nodes without names are announced by role instead
+        assert_eq!(
+            announce_list(&list(&tree, NavCategory::Landmark), NavCategory::Landmark),
+            "2 landmarks: Header, Main"
+        );
+        assert_eq!(
+            announce_list(&[], NavCategory::Button),
+            "There are no buttons on this page"
+        );
+    }
+
+    #[test]
+    fn announce_list_stops_after_the_maximum() {
+        let links: Vec<_> = (0..MAX_LISTED as u32 + 3)
+            .map(|i| Node::test(i, "link", &format!("Link {i}")))
+            .collect();
+        let nodes: Vec<_> = links.iter().collect();
+        let announced = announce_list(&nodes, NavCategory::Link);
+        assert!(announced.starts_with(&format!("{} links: Link 0, Link 1,", nodes.len())));
+        assert!(announced.contains(&format!("Link {}", MAX_LISTED - 1)));
+        assert!(!announced.contains(&format!("Link {}", MAX_LISTED)));
+        assert!(announced.ends_with(", and 3 more"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+/// A node in the accessibility tree of a page, filtered down to what's relevant.
+#[derive(Clone, Debug)]
+pub struct Node {
+    /// The ID of the DOM node underlying this node, which is stable for as long as the page
+    /// isn't reloaded.
+    pub dom_id: u32,
+    /// Whether or not this node can be acted upon. Nodes that can't be are only in the tree
+    /// to give context, and they don't get IDs the LLM can reference.
+    pub actionable: bool,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub role: Option<String>,
+    pub value: Option<String>,
+    pub properties: HashMap<String, String>,
+    /// Whether or not some of this node's children were left out of the snapshot of the
+    /// page this came from, in which case it can be expanded to get them.
+    pub collapsed: bool,
+    pub children: Vec<Node>,
+}
+impl Node {
+    /// Whether or not this node is plain text.
+    pub fn is_static_text(&self) -> bool {
+        self.role.as_deref() == Some("StaticText")
+    }
+    /// Converts the node into a string suitable for LLM ingestion. This deliberately elides
+    /// irrelevant information to save on tokens.
+    pub fn into_string(self, indent_level: usize) -> String {
+        let mut s = format!("{}{}", "\t".repeat(indent_level), self.line());
+        for child in self.children {
+            s.push('\n');
+            s.push_str(&child.into_string(indent_level + 1));
+        }
+        s
+    }
+    /// Converts the node into a single line in the same format as [`Node::into_string`], but
+    /// without its children or any indentation.
+    pub fn line(&self) -> String {
+        let is_static_text = self.is_static_text();
+        format!(
+            "- {id}{name}{role}{desc}{props}{value}{collapsed}",
+            // Only actionable nodes get IDs, so the LLM can't try to act on context
+            id = if self.actionable {
+                format!("[{}] ", self.dom_id)
+            } else {
+                String::new()
+            },
+            name = match &self.name {
+                Some(name) if !self.actionable && name.is_empty() => String::new(),
+                Some(name) => format!("\"{name}\""),
+                None if self.actionable => "\"<null>\"".to_string(),
+                None => String::new(),
+            },
+            // The role of text is obvious from the fact that it's only text
+            role = match &self.role {
+                Some(_) if is_static_text => String::new(),
+                Some(role) => format!(" ({role})"),
+                None => String::new(),
+            },
+            desc = if let Some(desc) = &self.description {
+                format!(" ({desc})")
+            } else {
+                String::new()
+            },
+            props = if !self.properties.is_empty() {
+                let props: Vec<_> = self
+                    .properties
+                    .iter()
+                    .map(|(key, val)| format!("{key}: {val}"))
+                    .collect();
+                format!(" {{{}}}", props.join(", "))
+            } else {
+                String::new()
+            },
+            value = if let Some(val) = &self.value {
+                format!(" with value {val}")
+            } else {
+                String::new()
+            },
+            // Collapsed nodes need IDs too, so the LLM can ask to expand them
+            collapsed = if self.collapsed {
+                format!(" [+{}]", self.dom_id)
+            } else {
+                String::new()
+            },
+        )
+    }
+    /// Converts the node into a plain outline of what the page says, for answering
+    /// questions about it. Unlike [`Node::into_string`], this has no IDs or properties.
+    pub fn into_content_string(self, indent_level: usize) -> String {
+        let is_static_text = self.is_static_text();
+        let mut s = format!(
+            "{tabs}-{role}{name}{value}",
+            tabs = "\t".repeat(indent_level),
+            role = match self.role {
+                Some(_) if is_static_text => String::new(),
+                Some(role) => format!(" ({role})"),
+                None => String::new(),
+            },
+            name = match self.name {
+                Some(name) if !name.is_empty() => format!(" {name}"),
+                _ => String::new(),
+            },
+            value = match self.value {
+                Some(val) if !val.is_empty() => format!(": {val}"),
+                _ => String::new(),
+            },
+        );
+        for child in self.children {
+            s.push('\n');
+            s.push_str(&child.into_content_string(indent_level + 1));
+        }
+        s
+    }
+}
+
+/// Flattens the given tree into a list of references to all its nodes, in document order
+/// (i.e. each node comes before its children, which come before its next sibling).
+pub fn flatten(tree: &[Node]) -> Vec<&Node> {
+    let mut nodes = Vec::new();
+    for node in tree {
+        nodes.push(node);
+        nodes.extend(flatten(&node.children));
+    }
+    nodes
+}
+
+#[cfg(test)]
+impl Node {
+    /// Creates an actionable node with the given DOM ID, role, and name, and nothing else, for
+    /// tests to build on.
+    pub(crate) fn test(dom_id: u32, role: &str, name: &str) -> Self {
+        Self {
+            dom_id,
+            actionable: true,
+            name: Some(name.to_string()),
+            description: None,
+            role: Some(role.to_string()),
+            value: None,
+            properties: HashMap::new(),
+            collapsed: false,
+            children: Vec::new(),
+        }
+    }
+    pub(crate) fn with_value(mut self, value: &str) -> Self {
+        self.value = Some(value.to_string());
+        self
+    }
+    pub(crate) fn with_property(mut self, key: &str, value: &str) -> Self {
+        self.properties.insert(key.to_string(), value.to_string());
+        self
+    }
+    pub(crate) fn with_children(mut self, children: Vec<Node>) -> Self {
+        self.children = children;
+        self
+    }
+    /// Makes the node context only, so it can't be acted on.
+    pub(crate) fn unactionable(mut self) -> Self {
+        self.actionable = false;
+        self
+    }
+    /// Marks the node as having children that were left out of the snapshot.
+    pub(crate) fn collapsed(mut self) -> Self {
+        self.collapsed = true;
+        self
+    }
+}
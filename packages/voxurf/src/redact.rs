@@ -0,0 +1,445 @@
+//! Redaction of sensitive values (like passwords, card numbers, and one-time codes) from the
+//! accessibility tree, so they're never sent to the LLM.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::node::Node;
+
+/// What redacted text is replaced with.
+pub const MASK: &str = "[redacted]";
+
+/// Tokens in an HTML `autocomplete` attribute that mean a field holds payment details or
+/// credentials.
+const SENSITIVE_AUTOCOMPLETE: &[&str] = &[
+    "cc-name",
+    "cc-number",
+    "cc-exp",
+    "cc-exp-month",
+    "cc-exp-year",
+    "cc-csc",
+    "current-password",
+    "new-password",
+    "one-time-code",
+];
+/// Patterns of text that look like card numbers. These are checked against the Luhn
+/// algorithm before being redacted, so most other long numbers are left alone.
+const CARD_PATTERN: &str = r"\b(?:\d[ -]?){12,18}\d\b";
+/// Patterns of text that look like US social security numbers.
+const SSN_PATTERN: &str = r"\b\d{3}-\d{2}-\d{4}\b";
+/// Patterns of text that look like email addresses.
+const EMAIL_PATTERN: &str = r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b";
+
+/// Rules for what should be redacted from the tree.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RedactionRules {
+    /// Whether or not to mask the values of password and other protected fields.
+    pub protected_fields: bool,
+    /// Whether or not to mask the values of fields whose `autocomplete` attribute says they
+    /// hold payment details or credentials (e.g. `cc-number`, `one-time-code`).
+    pub autocomplete_fields: bool,
+    /// Whether or not to mask anything that looks like a card number.
+    pub card_numbers: bool,
+    /// Whether or not to mask anything that looks like a social security number.
+    pub ssns: bool,
+    /// Whether or not to mask anything that looks like an email address.
+    pub emails: bool,
+    /// Any other regular expressions whose matches should be masked. Invalid ones are
+    /// ignored.
+    pub custom_patterns: Vec<String>,
+}
+impl Default for RedactionRules {
+    fn default() -> Self {
+        Self {
+            protected_fields: true,
+            autocomplete_fields: true,
+            card_numbers: true,
+            ssns: true,
+            emails: true,
+            custom_patterns: Vec::new(),
+        }
+    }
+}
+
+/// HTML attributes of a form field that aren't in the accessibility tree, but which say
+/// whether or not it holds something sensitive.
+#[derive(Clone, Debug, Default)]
+pub struct FieldAttributes {
+    /// The `type` attribute (e.g. `password`).
+    pub input_type: Option<String>,
+    /// The `autocomplete` attribute (e.g. `cc-number`).
+    pub autocomplete: Option<String>,
+}
+
+/// Why something was redacted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RedactionReason {
+    /// The field is a password or otherwise protected field.
+    ProtectedField,
+    /// The field's `autocomplete` attribute has the given sensitive token.
+    Autocomplete(String),
+    /// The text looks like a card number.
+    CardNumber,
+    /// The text looks like a social security number.
+    Ssn,
+    /// The text looks like an email address.
+    Email,
+    /// The text matches the given custom pattern.
+    CustomPattern(String),
+}
+impl fmt::Display for RedactionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ProtectedField => write!(f, "protected field"),
+            Self::Autocomplete(token) => write!(f, "autocomplete {token}"),
+            Self::CardNumber => write!(f, "card number"),
+            Self::Ssn => write!(f, "social security number"),
+            Self::Email => write!(f, "email address"),
+            Self::CustomPattern(pattern) => write!(f, "custom pattern {pattern}"),
+        }
+    }
+}
+
+/// A record of something that was redacted. This never includes what was redacted, only
+/// where it was and why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redaction {
+    /// The DOM ID of the node that had something redacted.
+    pub dom_id: u32,
+    pub role: Option<String>,
+    pub name: Option<String>,
+    pub reason: RedactionReason,
+}
+
+/// Redacts anything sensitive from the given tree, according to the given rules, returning
+/// records of everything that was redacted. The given attributes (keyed by DOM ID) are used
+/// to find sensitive fields, and any fields not in there are treated as having none.
+pub fn redact(
+    tree: &mut [Node],
+    rules: &RedactionRules,
+    attributes: &HashMap<u32, FieldAttributes>,
+) -> Vec<Redaction> {
+    let patterns = patterns(rules);
+    let mut redactions = Vec::new();
+    redact_nodes(tree, rules, attributes, &patterns, &mut redactions);
+    redactions
+}
+
+/// Redacts anything that looks sensitive from the given text (e.g. an error message the page
+/// logged), according to the given rules, in the same way as it would be from the tree.
+pub fn redact_text(text: &str, rules: &RedactionRules) -> String {
+    let mut text = text.to_string();
+    mask_patterns(&mut text, &patterns(rules));
+    text
+}
+
+/// Gets the patterns of sensitive text the given rules redact, with why each is redacted.
+fn patterns(rules: &RedactionRules) -> Vec<(Regex, RedactionReason)> {
+    let mut patterns = Vec::new();
+    if rules.card_numbers {
+        patterns.push((
+            Regex::new(CARD_PATTERN).unwrap(),
+            RedactionReason::CardNumber,
+        ));
+    }
+    if rules.ssns {
+        patterns.push((Regex::new(SSN_PATTERN).unwrap(), RedactionReason::Ssn));
+    }
+    if rules.emails {
+        patterns.push((Regex::new(EMAIL_PATTERN).unwrap(), RedactionReason::Email));
+    }
+    for pattern in &rules.custom_patterns {
+        if let Ok(re) = Regex::new(pattern) {
+            patterns.push((re, RedactionReason::CustomPattern(pattern.clone())));
+        }
+    }
+    patterns
+}
+
+/// Masks anything matching the given patterns in the given text, returning why anything was.
+fn mask_patterns(text: &mut String, patterns: &[(Regex, RedactionReason)]) -> Vec<RedactionReason> {
+    let mut reasons = Vec::new();
+    for (re, reason) in patterns {
+        let mut matched = false;
+        let masked = re.replace_all(text, |caps: &regex::Captures| {
+            if *reason == RedactionReason::CardNumber && !passes_luhn(&caps[0]) {
+                caps[0].to_string()
+            } else {
+                matched = true;
+                MASK.to_string()
+            }
+        });
+        if matched {
+            *text = masked.to_string();
+            if !reasons.contains(reason) {
+                reasons.push(reason.clone());
+            }
+        }
+    }
+    reasons
+}
+
+fn redact_nodes(
+    nodes: &mut [Node],
+    rules: &RedactionRules,
+    attributes: &HashMap<u32, FieldAttributes>,
+    patterns: &[(Regex, RedactionReason)],
+    redactions: &mut Vec<Redaction>,
+) {
+    for node in nodes {
+        let mut reasons = Vec::new();
+
+        // Whole fields first, in which case the entire value goes
+        let has_value = node.value.as_deref().is_some_and(|v| !v.is_empty());
+        if has_value {
+            if let Some(reason) = sensitive_field(node, rules, attributes.get(&node.dom_id)) {
+                node.value = Some(MASK.to_string());
+                reasons.push(reason);
+            }
+        }
+        // Then anything that looks sensitive wherever it is
+        for text in [&mut node.name, &mut node.description, &mut node.value]
+            .into_iter()
+            .flatten()
+        {
+            for reason in mask_patterns(text, patterns) {
+                if !reasons.contains(&reason) {
+                    reasons.push(reason);
+                }
+            }
+        }
+
+        for reason in reasons {
+            redactions.push(Redaction {
+                dom_id: node.dom_id,
+                role: node.role.clone(),
+                name: node.name.clone(),
+                reason,
+            });
+        }
+        redact_nodes(&mut node.children, rules, attributes, patterns, redactions);
+    }
+}
+
+/// Works out whether or not the given node is a field whose whole value is sensitive,
+/// returning why if it is.
+fn sensitive_field(
+    node: &Node,
+    rules: &RedactionRules,
+    attributes: Option<&FieldAttributes>,
+) -> Option<RedactionReason> {
+    if rules.protected_fields {
+        let is_protected = node
+            .properties
+            .get("protected")
+            .is_some_and(|p| p == "true")
+            || attributes
+                .and_then(|a| a.input_type.as_deref())
+                .is_some_and(|t| t.eq_ignore_ascii_case("password"));
+        if is_protected {
+            return Some(RedactionReason::ProtectedField);
+        }
+    }
+    if rules.autocomplete_fields {
+        // This can have several tokens, like `billing cc-number`
+        let autocomplete = attributes.and_then(|a| a.autocomplete.as_deref())?;
+        return autocomplete
+            .split_whitespace()
+            .map(|token| token.to_lowercase())
+            .find(|token| SENSITIVE_AUTOCOMPLETE.contains(&token.as_str()))
+            .map(RedactionReason::Autocomplete);
+    }
+    None
+}
+
+/// Checks whether or not the digits in the given text pass the Luhn checksum used by card
+/// numbers.
+fn passes_luhn(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| {
+            if i % 2 == 1 {
+                let doubled = d * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                d
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_sensitive_text() {
+        let rules = RedactionRules::default();
+        assert_eq!(
+            redact_text("Card 4111 1111 1111 1111 declined", &rules),
+            format!("Card {MASK} declined")
+        );
+        assert_eq!(
+            redact_text("No account for ann@example.com", &rules),
+            format!("No account for {MASK}")
+        );
+    }
+
+    #[test]
+    fn leaves_numbers_that_arent_card_numbers() {
+        let rules = RedactionRules::default();
+        assert_eq!(
+            redact_text("Order 4111 1111 1111 1112 shipped", &rules),
+            "Order 4111 1111 1111 1112 shipped"
+        );
+    }
+
+    #[test]
+    fn only_masks_what_the_rules_ask_for() {
+        let rules = RedactionRules {
+            emails: false,
+            custom_patterns: vec![r"ORD-\d+".to_string(), "(".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            redact_text("ann@example.com placed ORD-123", &rules),
+            format!("ann@example.com placed {MASK}")
+        );
+    }
+
+    fn attributes(entries: &[(u32, Option<&str>, Option<&str>)]) -> HashMap<u32, FieldAttributes> {
+        entries
+            .iter()
+            .map(|&(dom_id, input_type, autocomplete)| {
+                let attributes = FieldAttributes {
+                    input_type: input_type.map(String::from),
+                    autocomplete: autocomplete.map(String::from),
+                };
+                (dom_id, attributes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn masks_protected_fields() {
+        let mut tree = vec![Node::test(1, "form", "Sign in").with_children(vec![
+            Node::test(2, "textbox", "Password")
+                .with_value("hunter2")
+                .with_property("protected", "true"),
+            Node::test(3, "textbox", "PIN").with_value("1234"),
+            Node::test(4, "textbox", "Username").with_value("ann"),
+            Node::test(5, "textbox", "New password").with_property("protected", "true"),
+        ])];
+        let redactions = redact(
+            &mut tree,
+            &RedactionRules::default(),
+            &attributes(&[(3, Some("Password"), None)]),
+        );
+
+        let fields = &tree[0].children;
+        assert_eq!(fields[0].value.as_deref(), Some(MASK));
+        assert_eq!(fields[1].value.as_deref(), Some(MASK));
+        assert_eq!(fields[2].value.as_deref(), Some("ann"));
+        // Empty fields have nothing to hide
+        assert_eq!(fields[3].value, None);
+        assert_eq!(
+            redactions,
+            [
+                Redaction {
+                    dom_id: 2,
+                    role: Some("textbox".to_string()),
+                    name: Some("Password".to_string()),
+                    reason: RedactionReason::ProtectedField,
+                },
+                Redaction {
+                    dom_id: 3,
+                    role: Some("textbox".to_string()),
+                    name: Some("PIN".to_string()),
+                    reason: RedactionReason::ProtectedField,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn masks_fields_by_their_autocomplete_tokens() {
+        let mut tree = vec![
+            Node::test(1, "textbox", "Card").with_value("4111"),
+            Node::test(2, "textbox", "Code").with_value("987"),
+            Node::test(3, "textbox", "Address").with_value("1 Main St"),
+        ];
+        let attributes = attributes(&[
+            (1, None, Some("billing cc-number")),
+            (2, Some("text"), Some("CC-CSC")),
+            (3, None, Some("street-address")),
+        ]);
+        let redactions = redact(&mut tree, &RedactionRules::default(), &attributes);
+
+        assert_eq!(tree[0].value.as_deref(), Some(MASK));
+        assert_eq!(tree[1].value.as_deref(), Some(MASK));
+        assert_eq!(tree[2].value.as_deref(), Some("1 Main St"));
+        let reasons: Vec<_> = redactions.into_iter().map(|r| r.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                RedactionReason::Autocomplete("cc-number".to_string()),
+                RedactionReason::Autocomplete("cc-csc".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn field_rules_can_be_turned_off() {
+        let mut tree = vec![Node::test(1, "textbox", "Password")
+            .with_value("hunter2")
+            .with_property("protected", "true")];
+        let rules = RedactionRules {
+            protected_fields: false,
+            autocomplete_fields: false,
+            ..Default::default()
+        };
+        let attributes = attributes(&[(1, Some("password"), Some("current-password"))]);
+        assert!(redact(&mut tree, &rules, &attributes).is_empty());
+        assert_eq!(tree[0].value.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn masks_card_numbers_anywhere_in_the_tree() {
+        let mut tree = vec![Node::test(1, "main", "").with_children(vec![
+            Node::test(2, "StaticText", "Paying with 4111-1111-1111-1111").unactionable(),
+            Node::test(3, "textbox", "Card").with_value("4111 1111 1111 1111"),
+            Node::test(4, "StaticText", "Tracking 4111 1111 1111 1112").unactionable(),
+        ])];
+        let redactions = redact(&mut tree, &RedactionRules::default(), &HashMap::new());
+
+        let nodes = &tree[0].children;
+        assert_eq!(nodes[0].name, Some(format!("Paying with {MASK}")));
+        assert_eq!(nodes[1].value.as_deref(), Some(MASK));
+        // Numbers that fail the Luhn check aren't card numbers
+        assert_eq!(
+            nodes[2].name.as_deref(),
+            Some("Tracking 4111 1111 1111 1112")
+        );
+        let redacted: Vec<_> = redactions.iter().map(|r| (r.dom_id, &r.reason)).collect();
+        assert_eq!(
+            redacted,
+            [
+                (2, &RedactionReason::CardNumber),
+                (3, &RedactionReason::CardNumber)
+            ]
+        );
+        // Records only say where things were, not what they were
+        assert_eq!(redactions[0].name, Some(format!("Paying with {MASK}")));
+    }
+}
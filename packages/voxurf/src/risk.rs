@@ -0,0 +1,284 @@
+//! Assessment of how risky a planned action is, so that anything destructive or irreversible
+//! can be confirmed by the user before it happens.
+
+use regex::Regex;
+
+use crate::grammar::normalize;
+use crate::node::{flatten, Node};
+
+/// Words in the name of an element that mean acting on it probably can't be undone.
+const HIGH_RISK_WORDS: &[&str] = &[
+    "delete",
+    "remove",
+    "erase",
+    "discard",
+    "pay",
+    "purchase",
+    "buy",
+    "order",
+    "checkout",
+    "check out",
+    "send",
+    "transfer",
+    "unsubscribe",
+    "cancel subscription",
+    "close account",
+];
+/// Words in the name of an element that mean acting on it submits something.
+const SUBMIT_WORDS: &[&str] = &["submit", "save", "confirm", "post", "publish", "sign up"];
+/// Snippets of script that submit a form. Pressing Enter in a field usually does too.
+pub(crate) const SUBMIT_SNIPPETS: &[&str] = &[
+    ".submit()",
+    ".requestSubmit(",
+    "pressKey('Enter')",
+    "pressKey(\"Enter\")",
+];
+/// Snippets of script that navigate away from the page.
+pub(crate) const NAVIGATION_SNIPPETS: &[&str] = &[
+    "location.href",
+    "location.assign",
+    "location.replace",
+    "window.location =",
+    "document.location =",
+    "history.back",
+    "history.go",
+    "window.open",
+];
+/// Roles of fields the user might have typed into.
+const INPUT_ROLES: &[&str] = &["textbox", "searchbox", "combobox"];
+/// A regular expression for the ways a script can find elements other than by their DOM IDs,
+/// capturing what it's looking for (e.g. the selector given to `querySelector`, or the text an
+/// element's content is compared with).
+const DYNAMIC_LOOKUP_PATTERN: &str = r#"(?:querySelector(?:All)?|waitForElement|closest|getElementsBy\w+|getElementById|includes|startsWith|endsWith)\s*\(\s*(?:'([^']*)'|"([^"]*)"|`([^`]*)`)"#;
+
+/// How risky an action is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RiskLevel {
+    /// Nothing to worry about.
+    None,
+    /// Probably fine, but it might lose or submit something (e.g. submitting a form).
+    Low,
+    /// Probably destructive or irreversible (e.g. deleting something, paying for something).
+    High,
+}
+
+/// An assessment of how risky an action is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Risk {
+    /// The overall risk level, which is the highest of all the reasons.
+    pub level: RiskLevel,
+    /// Short descriptions of why the action is risky, suitable for reading out (e.g.
+    /// "click the Delete button").
+    pub reasons: Vec<String>,
+}
+impl Risk {
+    /// An assessment of an action with nothing to worry about.
+    fn none() -> Self {
+        Self {
+            level: RiskLevel::None,
+            reasons: Vec::new(),
+        }
+    }
+    fn add(&mut self, level: RiskLevel, reason: String) {
+        self.level = self.level.max(level);
+        if !self.reasons.contains(&reason) {
+            self.reasons.push(reason);
+        }
+    }
+}
+
+/// Assesses how risky it would be to run the given script, which acts on the given target
+/// nodes in the given tree.
+pub fn assess(script: &str, targets: &[&Node], tree: &[Node]) -> Risk {
+    let mut risk = Risk::none();
+
+    for target in targets {
+        let name = normalize(target.name.as_deref().unwrap_or(""));
+        let role = target.role.as_deref().unwrap_or("");
+        let describe = || {
+            format!(
+                "use the {} {role}",
+                target.name.as_deref().unwrap_or("unnamed")
+            )
+        };
+        if contains_word(&name, HIGH_RISK_WORDS) {
+            risk.add(RiskLevel::High, describe());
+        } else if role == "button" && contains_word(&name, SUBMIT_WORDS) {
+            risk.add(RiskLevel::Low, describe());
+        }
+    }
+
+    // Elements the script finds for itself were never in the tree we were given, so all we
+    // can go on is how it looks for them
+    let lookup_re = Regex::new(DYNAMIC_LOOKUP_PATTERN).unwrap();
+    for caps in lookup_re.captures_iter(script) {
+        let Some(lookup) = caps.get(1).or(caps.get(2)).or(caps.get(3)) else {
+            continue;
+        };
+        let describe = || format!("use an element matching \"{}\"", lookup.as_str());
+        let words = normalize(lookup.as_str());
+        if contains_word(&words, HIGH_RISK_WORDS) {
+            risk.add(RiskLevel::High, describe());
+        } else if contains_word(&words, SUBMIT_WORDS) {
+            risk.add(RiskLevel::Low, describe());
+        }
+    }
+
+    if SUBMIT_SNIPPETS.iter().any(|s| script.contains(s)) {
+        risk.add(RiskLevel::Low, "submit a form".to_string());
+    }
+
+    let navigates = NAVIGATION_SNIPPETS.iter().any(|s| script.contains(s))
+        || targets.iter().any(|t| t.role.as_deref() == Some("link"));
+    if navigates {
+        add_leaving_risk(&mut risk, tree);
+    }
+
+    risk
+}
+
+/// Assesses how risky it would be to leave the page with the given tree, whether by going
+/// back, reloading, closing its tab, or going somewhere else in it.
+pub fn assess_leaving(tree: &[Node]) -> Risk {
+    let mut risk = Risk::none();
+    add_leaving_risk(&mut risk, tree);
+    risk
+}
+
+/// Assesses how risky it would be to press the given key (e.g. `Enter`) on the page with the
+/// given tree, which depends on what has focus.
+pub fn assess_key(key: &str, tree: &[Node]) -> Risk {
+    let mut risk = Risk::none();
+    let nodes = flatten(tree);
+    let focused = nodes
+        .iter()
+        .find(|n| n.properties.get("focused").is_some_and(|v| v == "true"));
+    let Some(focused) = focused else {
+        return risk;
+    };
+    let role = focused.role.as_deref().unwrap_or("");
+    let multiline = focused
+        .properties
+        .get("multiline")
+        .is_some_and(|v| v == "true");
+
+    if INPUT_ROLES.contains(&role) {
+        // Enter in a single-line field usually submits its form
+        if key == "Enter" && !multiline {
+            risk.add(RiskLevel::Low, "submit a form".to_string());
+        }
+        risk
+    } else if key == "Enter" || key == " " {
+        // These activate whatever has focus, just like clicking it would
+        assess("", &[focused], tree)
+    } else {
+        risk
+    }
+}
+
+/// Adds the risk of losing what's been typed into the page with the given tree, if anything
+/// has, to the given risk.
+fn add_leaving_risk(risk: &mut Risk, tree: &[Node]) {
+    let has_input = flatten(tree).into_iter().any(|n| {
+        INPUT_ROLES.contains(&n.role.as_deref().unwrap_or(""))
+            && n.value.as_deref().is_some_and(|v| !v.trim().is_empty())
+    });
+    if has_input {
+        risk.add(
+            RiskLevel::Low,
+            "leave this page, losing what's been typed into its form".to_string(),
+        );
+    }
+}
+
+/// Checks whether the given normalized text contains any of the given words (or phrases) as
+/// whole words.
+fn contains_word(text: &str, words: &[&str]) -> bool {
+    let padded = format!(" {text} ");
+    words.iter().any(|w| padded.contains(&format!(" {w} ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_risk_words_make_an_action_high_risk() {
+        let delete = Node::test(1, "button", "Delete account");
+        let risk = assess("", &[&delete], &[]);
+        assert_eq!(risk.level, RiskLevel::High);
+        assert_eq!(risk.reasons, ["use the Delete account button"]);
+
+        let save = Node::test(2, "button", "Save");
+        assert_eq!(assess("", &[&save], &[]).level, RiskLevel::Low);
+
+        let help = Node::test(3, "button", "Help");
+        assert_eq!(assess("", &[&help], &[]), Risk::none());
+    }
+
+    #[test]
+    fn words_only_match_whole_words() {
+        // Any use of a risky word counts, even when the element itself is harmless
+        let history = Node::test(1, "button", "Order history");
+        assert_eq!(assess("", &[&history], &[]).level, RiskLevel::High);
+
+        let border = Node::test(2, "button", "Borders");
+        assert_eq!(assess("", &[&border], &[]).level, RiskLevel::None);
+        let display = Node::test(3, "button", "Display settings");
+        assert_eq!(assess("", &[&display], &[]).level, RiskLevel::None);
+    }
+
+    #[test]
+    fn elements_found_by_the_script_are_assessed_by_how_theyre_found() {
+        let risk = assess("click(querySelector('button.delete'));", &[], &[]);
+        assert_eq!(risk.level, RiskLevel::High);
+        assert_eq!(risk.reasons, ["use an element matching \"button.delete\""]);
+    }
+
+    #[test]
+    fn pressing_enter_in_a_script_submits() {
+        let risk = assess("await pressKey('Enter');", &[], &[]);
+        assert_eq!(risk.level, RiskLevel::Low);
+        assert_eq!(risk.reasons, ["submit a form"]);
+    }
+
+    #[test]
+    fn pressing_enter_in_a_single_line_field_submits() {
+        let field = |multiline| {
+            vec![Node::test(1, "textbox", "Search")
+                .with_property("focused", "true")
+                .with_property("multiline", multiline)]
+        };
+        assert_eq!(assess_key("Enter", &field("false")).level, RiskLevel::Low);
+        assert_eq!(assess_key("Enter", &field("true")).level, RiskLevel::None);
+        assert_eq!(assess_key("a", &field("false")).level, RiskLevel::None);
+    }
+
+    #[test]
+    fn pressing_enter_activates_whatever_has_focus() {
+        let tree = vec![Node::test(1, "button", "Buy now").with_property("focused", "true")];
+        assert_eq!(assess_key("Enter", &tree).level, RiskLevel::High);
+        assert_eq!(assess_key(" ", &tree).level, RiskLevel::High);
+        assert_eq!(assess_key("Tab", &tree).level, RiskLevel::None);
+        // Nothing happens without focus
+        assert_eq!(assess_key("Enter", &[]), Risk::none());
+    }
+
+    #[test]
+    fn leaving_a_page_with_typed_input_is_risky() {
+        let typed = vec![Node::test(1, "textbox", "Message").with_value("Hello")];
+        let empty = vec![Node::test(1, "textbox", "Message").with_value("  ")];
+        let link = Node::test(2, "link", "Home");
+
+        let risk = assess("location.href = '/';", &[], &typed);
+        assert_eq!(risk.level, RiskLevel::Low);
+        assert_eq!(
+            risk.reasons,
+            ["leave this page, losing what's been typed into its form"]
+        );
+        assert_eq!(assess("", &[&link], &typed).level, RiskLevel::Low);
+        assert_eq!(assess("location.href = '/';", &[], &empty), Risk::none());
+        assert_eq!(assess("", &[], &typed), Risk::none());
+        assert_eq!(assess_leaving(&typed).level, RiskLevel::Low);
+    }
+}
@@ -0,0 +1,276 @@
+//! Working out which steps can be undone, which is only those that just change the state of
+//! form fields, since that state can be put back the way it was, and keeping track of them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::locator::Locator;
+use crate::node::Node;
+use crate::risk::{NAVIGATION_SNIPPETS, SUBMIT_SNIPPETS};
+
+/// How many steps are remembered, after which the oldest are forgotten.
+pub const MAX_UNDO_STEPS: usize = 50;
+
+/// Roles of elements whose state can be recorded and put back.
+pub const RESTORABLE_ROLES: &[&str] = &[
+    "textbox",
+    "searchbox",
+    "combobox",
+    "spinbutton",
+    "slider",
+    "checkbox",
+    "radio",
+    "switch",
+    "listbox",
+];
+
+/// A step that was run on the page, with what its elements were like beforehand so it can be
+/// undone.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UndoStep {
+    /// What the step did, as it was described when it was run.
+    pub description: String,
+    /// Why the step can't be undone (e.g. "it submitted a form"), if it can't.
+    pub not_undoable: Option<String>,
+    /// Whether or not the step changed the page so much (e.g. by going to another one) that
+    /// nothing before it can be undone either.
+    #[serde(default)]
+    pub barrier: bool,
+    /// The elements the step changed, with how they were before it.
+    pub targets: Vec<UndoTarget>,
+}
+impl UndoStep {
+    /// Creates a step with the given description that can't be undone for the given reason,
+    /// and that nothing before can be undone past either.
+    pub fn barrier(description: &str, reason: &str) -> Self {
+        Self {
+            description: description.to_string(),
+            not_undoable: Some(reason.to_string()),
+            barrier: true,
+            targets: Vec::new(),
+        }
+    }
+}
+
+/// An element changed by a step, with enough information to find it again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UndoTarget {
+    /// The DOM ID the element had when the step was run.
+    pub dom_id: u32,
+    /// How to find the element again, if its DOM ID has changed.
+    #[serde(flatten)]
+    pub locator: Locator,
+    /// What the element was like before the step.
+    pub state: ElementState,
+}
+
+/// The state of an element that a step can change.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ElementState {
+    /// The element's value, or its text if it's editable content.
+    pub value: Option<String>,
+    /// Whether or not the element was checked, if it can be.
+    pub checked: Option<bool>,
+}
+
+/// A step that couldn't be undone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Blocked {
+    /// What the step did, as it was described when it was run.
+    pub description: String,
+    /// Why it can't be undone, suitable for reading out.
+    pub reason: String,
+}
+
+/// Adds the given step to the end of the given steps, oldest first, forgetting the oldest if
+/// there are too many.
+pub fn push_step(steps: &mut Vec<UndoStep>, step: UndoStep) {
+    steps.push(step);
+    if steps.len() > MAX_UNDO_STEPS {
+        steps.drain(..steps.len() - MAX_UNDO_STEPS);
+    }
+}
+
+/// Takes the most recent step off the given steps, oldest first, so it can be undone, or
+/// returns why it can't be. A step that can't be undone is taken off all the same, so undoing
+/// again carries on past it, unless it's a barrier, in which case every step before it is
+/// forgotten too, since they were taken on a page that's no longer there.
+pub fn pop_step(steps: &mut Vec<UndoStep>) -> Option<Result<UndoStep, Blocked>> {
+    let step = steps.pop()?;
+    let Some(reason) = step.not_undoable else {
+        return Some(Ok(step));
+    };
+    if step.barrier {
+        steps.clear();
+    }
+    Some(Err(Blocked {
+        description: step.description,
+        reason,
+    }))
+}
+
+/// Works out whether the given step, which runs the given script on the given target nodes,
+/// is a barrier that nothing before it can be undone past (e.g. because it went to another
+/// page).
+pub fn is_barrier(script: &str, targets: &[&Node]) -> bool {
+    NAVIGATION_SNIPPETS.iter().any(|s| script.contains(s))
+        || SUBMIT_SNIPPETS.iter().any(|s| script.contains(s))
+        || targets.iter().any(|t| t.role.as_deref() == Some("link"))
+}
+
+/// Works out why the given step, which runs the given script on the given target nodes,
+/// can't be undone, returning a reason that can be read out (e.g. "it submitted a form"), or
+/// `None` if it can be.
+pub fn why_not_undoable(script: &str, targets: &[&Node]) -> Option<String> {
+    if NAVIGATION_SNIPPETS.iter().any(|s| script.contains(s)) {
+        return Some("it went to another page".to_string());
+    }
+    if SUBMIT_SNIPPETS.iter().any(|s| script.contains(s)) {
+        return Some("it submitted a form".to_string());
+    }
+    if targets.is_empty() {
+        return Some("it didn't change any particular element".to_string());
+    }
+    for target in targets {
+        let name = target
+            .name
+            .as_deref()
+            .filter(|n| !n.is_empty())
+            .unwrap_or("unnamed");
+        match target.role.as_deref() {
+            Some(role) if RESTORABLE_ROLES.contains(&role) => {}
+            Some("link") => return Some(format!("it followed the {name} link")),
+            Some(role) => return Some(format!("it used the {name} {role}")),
+            None => return Some(format!("it used the {name} element")),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(description: &str) -> UndoStep {
+        UndoStep {
+            description: description.to_string(),
+            not_undoable: None,
+            barrier: false,
+            targets: Vec::new(),
+        }
+    }
+
+    fn not_undoable(description: &str, reason: &str) -> UndoStep {
+        UndoStep {
+            not_undoable: Some(reason.to_string()),
+            ..step(description)
+        }
+    }
+
+    fn blocked(description: &str, reason: &str) -> Blocked {
+        Blocked {
+            description: description.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn undoes_the_newest_step_first() {
+        let mut steps = Vec::new();
+        push_step(&mut steps, step("Typed the name"));
+        push_step(&mut steps, step("Typed the email"));
+        assert_eq!(pop_step(&mut steps), Some(Ok(step("Typed the email"))));
+        assert_eq!(pop_step(&mut steps), Some(Ok(step("Typed the name"))));
+        assert_eq!(pop_step(&mut steps), None);
+    }
+
+    #[test]
+    fn forgets_the_oldest_steps_when_there_are_too_many() {
+        let mut steps = Vec::new();
+        for i in 0..MAX_UNDO_STEPS + 5 {
+            push_step(&mut steps, step(&i.to_string()));
+        }
+        assert_eq!(steps.len(), MAX_UNDO_STEPS);
+        assert_eq!(steps[0].description, "5");
+        assert_eq!(
+            steps.last().unwrap().description,
+            (MAX_UNDO_STEPS + 4).to_string()
+        );
+    }
+
+    #[test]
+    fn stops_at_steps_that_cant_be_undone_and_carries_on_past_them() {
+        let mut steps = vec![
+            step("Typed the name"),
+            not_undoable("Opened the menu", "it used the Menu button"),
+            step("Typed the email"),
+        ];
+        assert_eq!(pop_step(&mut steps), Some(Ok(step("Typed the email"))));
+        assert_eq!(
+            pop_step(&mut steps),
+            Some(Err(blocked("Opened the menu", "it used the Menu button")))
+        );
+        assert_eq!(pop_step(&mut steps), Some(Ok(step("Typed the name"))));
+    }
+
+    #[test]
+    fn forgets_everything_before_a_barrier() {
+        let mut steps = vec![
+            step("Typed the name"),
+            UndoStep::barrier("Went back", "it went to another page"),
+            step("Typed the search"),
+        ];
+        assert_eq!(pop_step(&mut steps), Some(Ok(step("Typed the search"))));
+        assert_eq!(
+            pop_step(&mut steps),
+            Some(Err(blocked("Went back", "it went to another page")))
+        );
+        assert_eq!(pop_step(&mut steps), None);
+    }
+
+    #[test]
+    fn only_form_fields_can_be_undone() {
+        let name = Node::test(1, "textbox", "Name");
+        let agree = Node::test(1, "checkbox", "Agree");
+        let delete = Node::test(1, "button", "Delete");
+        let home = Node::test(1, "link", "Home");
+        assert_eq!(why_not_undoable("", &[&name, &agree]), None);
+        assert_eq!(
+            why_not_undoable("", &[&name, &delete]),
+            Some("it used the Delete button".to_string())
+        );
+        assert_eq!(
+            why_not_undoable("", &[&home]),
+            Some("it followed the Home link".to_string())
+        );
+        assert_eq!(
+            why_not_undoable("", &[]),
+            Some("it didn't change any particular element".to_string())
+        );
+    }
+
+    #[test]
+    fn submitting_or_navigating_cant_be_undone() {
+        let name = Node::test(1, "textbox", "Name");
+        assert_eq!(
+            why_not_undoable("elementFromId(1).form.requestSubmit();", &[&name]),
+            Some("it submitted a form".to_string())
+        );
+        assert_eq!(
+            why_not_undoable("location.href = '/';", &[&name]),
+            Some("it went to another page".to_string())
+        );
+    }
+
+    #[test]
+    fn leaving_the_page_is_a_barrier() {
+        let name = Node::test(1, "textbox", "Name");
+        let home = Node::test(1, "link", "Home");
+        let delete = Node::test(1, "button", "Delete");
+        assert!(is_barrier("history.back();", &[]));
+        assert!(is_barrier("elementFromId(1).form.submit();", &[&name]));
+        assert!(is_barrier("", &[&home]));
+        assert!(!is_barrier("", &[&name]));
+        assert!(!is_barrier("", &[&delete]));
+    }
+}
@@ -0,0 +1,486 @@
+//! Validation of what the LLM replies with, before anything is run on the page, so that
+//! mistakes can be explained back to it precisely rather than failing halfway through.
+
+use regex::Regex;
+use std::fmt;
+
+use crate::node::{flatten, Node};
+
+/// Operations scripts aren't allowed to use, as regular expressions, along with descriptions
+/// of them for the LLM. Scripts only need to act on the page the user can see, and none of
+/// these are ever needed for that.
+const FORBIDDEN_OPERATIONS: &[(&str, &str)] = &[
+    (r"\bfetch\s*\(", "making network requests with `fetch`"),
+    (
+        r"\bXMLHttpRequest\b",
+        "making network requests with `XMLHttpRequest`",
+    ),
+    (r"\bWebSocket\b", "opening WebSockets"),
+    (r"\bsendBeacon\b", "sending beacons"),
+    (r"\bdocument\.cookie\b", "reading or changing cookies"),
+    (
+        r"\b(localStorage|sessionStorage|indexedDB)\b",
+        "using the page's storage",
+    ),
+    (
+        r"\beval\s*\(|\bnew\s+Function\b",
+        "running code from strings",
+    ),
+    (r"\bdocument\.write(ln)?\s*\(", "rewriting the document"),
+    (r"\bchrome\.", "using browser extension APIs"),
+];
+/// The pattern of the placeholders scripts use for variables (e.g. `{{var:email}}`), which
+/// captures the variable's name as it's written.
+pub const PLACEHOLDER_PATTERN: &str = r"\{\{\s*var:\s*([^}\s]+)\s*\}\}";
+/// Keywords after which a `/` starts a regular expression literal, rather than dividing.
+const REGEX_PRECEDING_KEYWORDS: &[&str] = &["return", "typeof", "case", "do", "else", "in", "of"];
+
+/// Something wrong with what the LLM replied with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The reply had neither a status nor a request to expand part of the tree or find nodes.
+    NoStatus,
+    /// The reply's status couldn't be read, for the given reason.
+    InvalidStatus(String),
+    /// The reply said there's more to do, but didn't give a script for this step.
+    MissingScript,
+    /// The reply had a script, but its status (which is given) means nothing should be done.
+    UnexpectedScript(&'static str),
+    /// The reply said the user needs to be asked something, but didn't say what.
+    MissingQuestion,
+    /// The reply said several elements match what the user said, but didn't give at least two
+    /// of them to choose from.
+    MissingCandidates,
+    /// The script referenced an element that isn't in the tree.
+    UnknownElement(u32),
+    /// The script referenced an element that's in the tree, but can't be acted on.
+    NotActionable(u32),
+    /// The script referenced an element with something other than a literal ID.
+    DynamicElementReference(String),
+    /// The script used an operation it isn't allowed to, described for the LLM.
+    ForbiddenOperation(&'static str),
+    /// The script used a variable that doesn't exist.
+    UnknownVariable(String),
+    /// The script put the placeholder of the given variable inside a string or comment, rather
+    /// than using it as an expression.
+    VariableInString(String),
+    /// The LLM asked to expand a node that isn't in the tree, or that has nothing hidden
+    /// inside it.
+    NotExpandable(u32),
+    /// The LLM asked to expand something that can't be a node ID at all, which is given.
+    InvalidExpand(String),
+}
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoStatus => write!(
+                f,
+                "Your reply had neither a `json` status nor an `EXPAND` or `FIND` request."
+            ),
+            Self::InvalidStatus(reason) => {
+                write!(f, "Your `json` status couldn't be read: {reason}.")
+            }
+            Self::MissingScript => write!(
+                f,
+                "Your status was `continue`, but there was no `js` script for this step."
+            ),
+            Self::UnexpectedScript(status) => write!(
+                f,
+                "Your status was `{status}`, but there was a `js` script; only `done` and `continue` can have one, so leave it out."
+            ),
+            Self::MissingQuestion => write!(
+                f,
+                "Your status was `need_user_input`, but there was no `question` for the user."
+            ),
+            Self::MissingCandidates => write!(
+                f,
+                "Your status was `ambiguous`, but there weren't at least two node IDs in `candidates`."
+            ),
+            Self::UnknownElement(id) => write!(f, "There's no node with the ID {id} on the page."),
+            Self::NotActionable(id) => write!(
+                f,
+                "Node {id} can't be acted on; only nodes with an ID in square brackets can."
+            ),
+            Self::DynamicElementReference(arg) => write!(
+                f,
+                "`elementFromId({arg})` has to be given a node ID as a plain number, like `elementFromId(500)`."
+            ),
+            Self::ForbiddenOperation(description) => {
+                write!(f, "Scripts aren't allowed to do this: {description}.")
+            }
+            Self::UnknownVariable(name) => write!(
+                f,
+                "There's no variable called `{name}`; only the variables listed can be used."
+            ),
+            Self::VariableInString(name) => write!(
+                f,
+                "`{{{{var:{name}}}}}` is inside a string or comment; placeholders stand for the whole value, so use them on their own as expressions, like `typeInto(el, {{{{var:{name}}}}})` or `'Hello ' + {{{{var:{name}}}}}`."
+            ),
+            Self::NotExpandable(id) => write!(
+                f,
+                "Node {id} isn't marked with a plus, so there's nothing more in it to show."
+            ),
+            Self::InvalidExpand(arg) => write!(
+                f,
+                "`EXPAND {arg}` doesn't give the ID of a node; use the ID of a node marked with a plus."
+            ),
+        }
+    }
+}
+
+/// Checks the given script, which was written by the LLM against the given tree, for
+/// references to elements it can't act on and for operations it isn't allowed to use. This
+/// returns everything wrong with it, which will be empty if it's fine to run.
+pub fn validate_script(script: &str, tree: &[Node]) -> Vec<Violation> {
+    let nodes = flatten(tree);
+    let mut violations = Vec::new();
+
+    let reference_re = Regex::new(r"elementFromId\s*\(([^)]*)\)").unwrap();
+    for caps in reference_re.captures_iter(script) {
+        let arg = caps[1].trim();
+        let violation = match arg.parse::<u32>() {
+            // References with spaces in them wouldn't be found when the script is run
+            Ok(_) if caps[0] != format!("elementFromId({arg})") => {
+                Violation::DynamicElementReference(arg.to_string())
+            }
+            Ok(id) => match nodes.iter().find(|n| n.dom_id == id) {
+                Some(node) if node.actionable => continue,
+                Some(_) => Violation::NotActionable(id),
+                None => Violation::UnknownElement(id),
+            },
+            Err(_) => Violation::DynamicElementReference(arg.to_string()),
+        };
+        if !violations.contains(&violation) {
+            violations.push(violation);
+        }
+    }
+
+    for (pattern, description) in FORBIDDEN_OPERATIONS {
+        if Regex::new(pattern).unwrap().is_match(script) {
+            violations.push(Violation::ForbiddenOperation(description));
+        }
+    }
+
+    for name in placeholders_in_strings(&unquote_placeholders(script)) {
+        let violation = Violation::VariableInString(name);
+        if !violations.contains(&violation) {
+            violations.push(violation);
+        }
+    }
+
+    violations
+}
+
+/// Checks that the nodes with the given DOM IDs, which the LLM wants the user to choose
+/// between, are in the given tree, can be acted on, and that there are at least two of them.
+pub fn validate_candidates(ids: &[u32], tree: &[Node]) -> Vec<Violation> {
+    let nodes = flatten(tree);
+    let mut violations = Vec::new();
+    if ids.len() < 2 {
+        violations.push(Violation::MissingCandidates);
+    }
+    for &id in ids {
+        match nodes.iter().find(|n| n.dom_id == id) {
+            Some(node) if node.actionable => {}
+            Some(_) => violations.push(Violation::NotActionable(id)),
+            None => violations.push(Violation::UnknownElement(id)),
+        }
+    }
+    violations
+}
+
+/// Checks that the node with the given DOM ID, which the LLM asked to expand, is in the given
+/// tree and has something hidden inside it.
+pub fn validate_expand(id: u32, tree: &[Node]) -> Vec<Violation> {
+    if flatten(tree).iter().any(|n| n.dom_id == id && n.collapsed) {
+        Vec::new()
+    } else {
+        vec![Violation::NotExpandable(id)]
+    }
+}
+
+/// Turns any variable placeholders in the given script that are the whole of a string
+/// literal (e.g. `'{{var:email}}'`) into bare placeholders, since they mean the same thing.
+pub fn unquote_placeholders(script: &str) -> String {
+    let re = Regex::new(&format!(
+        "'({PLACEHOLDER_PATTERN})'|\"({PLACEHOLDER_PATTERN})\"|`({PLACEHOLDER_PATTERN})`"
+    ))
+    .unwrap();
+    re.replace_all(script, |caps: &regex::Captures| {
+        [1, 3, 5]
+            .into_iter()
+            .find_map(|i| caps.get(i))
+            .map(|m| m.as_str().to_string())
+            .unwrap_or_default()
+    })
+    .to_string()
+}
+
+/// Finds the variable placeholders in the given script that are inside string literals,
+/// regular expression literals, or comments, where they'd be taken as part of the text rather
+/// than as the values they stand for. This gives their names as they're written.
+pub fn placeholders_in_strings(script: &str) -> Vec<String> {
+    let inside = text_positions(script);
+    let mut names = Vec::new();
+    for caps in Regex::new(PLACEHOLDER_PATTERN)
+        .unwrap()
+        .captures_iter(script)
+    {
+        let name = caps[1].to_string();
+        if inside[caps.get(0).unwrap().start()] && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Works out which bytes of the given script are part of a string literal, regular expression
+/// literal, or comment, rather than code. This only needs to be good enough for the scripts
+/// the LLM writes, so it doesn't handle expressions inside template literals.
+fn text_positions(script: &str) -> Vec<bool> {
+    #[derive(PartialEq)]
+    enum State {
+        Code,
+        Quoted(u8),
+        Regex { in_class: bool },
+        LineComment,
+        BlockComment,
+    }
+
+    let bytes = script.as_bytes();
+    let mut inside = vec![false; bytes.len()];
+    let mut state = State::Code;
+    // The last thing in the code that wasn't whitespace, which tells us whether a `/` divides
+    // or starts a regular expression
+    let mut last_token = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        inside[i] = state != State::Code;
+        match state {
+            State::Code => match b {
+                b'\'' | b'"' | b'`' => {
+                    inside[i] = true;
+                    state = State::Quoted(b);
+                }
+                b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                    inside[i] = true;
+                    state = State::LineComment;
+                }
+                b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                    inside[i] = true;
+                    state = State::BlockComment;
+                }
+                b'/' if starts_regex(&last_token) => {
+                    inside[i] = true;
+                    state = State::Regex { in_class: false };
+                }
+                _ if b.is_ascii_whitespace() => {}
+                _ if b.is_ascii_alphanumeric() || b == b'_' || b == b'$' => {
+                    let continues_word = last_token
+                        .bytes()
+                        .last()
+                        .is_some_and(|l| l.is_ascii_alphanumeric() || l == b'_' || l == b'$')
+                        && i > 0
+                        && !bytes[i - 1].is_ascii_whitespace();
+                    if !continues_word {
+                        last_token.clear();
+                    }
+                    last_token.push(b as char);
+                }
+                _ => {
+                    last_token.clear();
+                    last_token.push(b as char);
+                }
+            },
+            State::Quoted(quote) => {
+                if b == b'\\' {
+                    if i + 1 < bytes.len() {
+                        inside[i + 1] = true;
+                    }
+                    i += 1;
+                } else if b == quote {
+                    state = State::Code;
+                    last_token = "a".to_string();
+                }
+            }
+            State::Regex { in_class } => {
+                if b == b'\\' {
+                    if i + 1 < bytes.len() {
+                        inside[i + 1] = true;
+                    }
+                    i += 1;
+                } else if b == b'[' {
+                    state = State::Regex { in_class: true };
+                } else if b == b']' {
+                    state = State::Regex { in_class: false };
+                } else if b == b'/' && !in_class {
+                    state = State::Code;
+                    last_token = "a".to_string();
+                }
+            }
+            State::LineComment => {
+                if b == b'\n' {
+                    state = State::Code;
+                }
+            }
+            State::BlockComment => {
+                if b == b'/' && i > 0 && bytes[i - 1] == b'*' {
+                    state = State::Code;
+                }
+            }
+        }
+        i += 1;
+    }
+    inside
+}
+
+/// Whether or not a `/` after the given token (the last one before it) starts a regular
+/// expression literal, rather than dividing.
+fn starts_regex(last_token: &str) -> bool {
+    match last_token.bytes().last() {
+        None => true,
+        Some(b')' | b']' | b'}') => false,
+        Some(b) if b.is_ascii_alphanumeric() || b == b'_' || b == b'$' => {
+            REGEX_PRECEDING_KEYWORDS.contains(&last_token)
+        }
+        Some(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> Vec<Node> {
+        vec![Node::test(1, "form", "Form")
+            .unactionable()
+            .with_children(vec![
+                Node::test(2, "button", "Send"),
+                Node::test(3, "button", "More").collapsed(),
+            ])]
+    }
+
+    #[test]
+    fn accepts_scripts_using_actionable_nodes() {
+        let script = "await clickElement(elementFromId(2));\nelementFromId(3).focus();";
+        assert!(validate_script(script, &tree()).is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_and_unactionable_nodes() {
+        let script =
+            "elementFromId(1).click(); elementFromId(9).click(); elementFromId(9).focus();";
+        assert_eq!(
+            validate_script(script, &tree()),
+            [Violation::NotActionable(1), Violation::UnknownElement(9)]
+        );
+    }
+
+    #[test]
+    fn rejects_dynamic_references() {
+        assert_eq!(
+            validate_script("elementFromId(ids[0]).click();", &tree()),
+            [Violation::DynamicElementReference("ids[0]".to_string())]
+        );
+        assert_eq!(
+            validate_script("elementFromId( 2 ).click();", &tree()),
+            [Violation::DynamicElementReference("2".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_forbidden_operations() {
+        let violations = validate_script(
+            "fetch('/api'); localStorage.setItem('a', 'b'); elementFromId(2).click();",
+            &tree(),
+        );
+        assert_eq!(
+            violations,
+            [
+                Violation::ForbiddenOperation("making network requests with `fetch`"),
+                Violation::ForbiddenOperation("using the page's storage"),
+            ]
+        );
+    }
+
+    #[test]
+    fn doesnt_mistake_similar_names_for_forbidden_operations() {
+        let script = "const prefetch = 1; elementFromId(2).dataset.cookieBanner = 'no';";
+        assert!(validate_script(script, &tree()).is_empty());
+    }
+
+    #[test]
+    fn needs_at_least_two_valid_candidates() {
+        assert!(validate_candidates(&[2, 3], &tree()).is_empty());
+        assert_eq!(
+            validate_candidates(&[2], &tree()),
+            [Violation::MissingCandidates]
+        );
+        assert_eq!(
+            validate_candidates(&[1, 2, 9], &tree()),
+            [Violation::NotActionable(1), Violation::UnknownElement(9)]
+        );
+    }
+
+    #[test]
+    fn only_collapsed_nodes_can_be_expanded() {
+        assert!(validate_expand(3, &tree()).is_empty());
+        assert_eq!(validate_expand(2, &tree()), [Violation::NotExpandable(2)]);
+        assert_eq!(validate_expand(9, &tree()), [Violation::NotExpandable(9)]);
+    }
+
+    #[test]
+    fn explains_invalid_expand_requests() {
+        assert_eq!(
+            Violation::InvalidExpand("the form".to_string()).to_string(),
+            "`EXPAND the form` doesn't give the ID of a node; use the ID of a node marked with a plus."
+        );
+    }
+
+    #[test]
+    fn allows_placeholders_used_as_expressions() {
+        let script = "await typeInto(elementFromId(2), {{var:email}});\n\
+                      copyToClipboard('Dear ' + {{var:name}} + '!');";
+        assert!(placeholders_in_strings(script).is_empty());
+        assert!(validate_script(script, &tree()).is_empty());
+    }
+
+    #[test]
+    fn unquotes_placeholders_that_are_whole_strings() {
+        assert_eq!(
+            unquote_placeholders("f('{{var:a}}', \"{{var:b}}\", `{{var:c}}`, 'x {{var:d}}')"),
+            "f({{var:a}}, {{var:b}}, {{var:c}}, 'x {{var:d}}')"
+        );
+        assert!(
+            validate_script("typeInto(elementFromId(2), '{{var:email}}');", &tree()).is_empty()
+        );
+    }
+
+    #[test]
+    fn rejects_placeholders_inside_strings_and_comments() {
+        assert_eq!(
+            placeholders_in_strings(
+                "a('Hi {{var:name}}'); b(`${x} {{var:city}}`); // {{var:zip}}\n/* {{var:pin}} */"
+            ),
+            ["name", "city", "zip", "pin"]
+        );
+        assert_eq!(
+            validate_script("typeInto(elementFromId(2), 'Hi {{var:name}}');", &tree()),
+            [Violation::VariableInString("name".to_string())]
+        );
+    }
+
+    #[test]
+    fn tells_regular_expressions_from_division() {
+        // The quote in the regular expression doesn't start a string
+        assert!(placeholders_in_strings("s.replace(/'/g, '') + {{var:a}}").is_empty());
+        assert_eq!(
+            placeholders_in_strings("s.replace(/'/g, \"x {{var:a}}\")"),
+            ["a"]
+        );
+        // Whereas after a value, a slash divides
+        assert!(placeholders_in_strings("(a) / 2 + {{var:b}} / 'x'.length").is_empty());
+        assert_eq!(placeholders_in_strings("return /{{var:c}}/.test(s)"), ["c"]);
+    }
+}
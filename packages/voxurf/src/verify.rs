@@ -0,0 +1,195 @@
+//! Rules for checking that the user's goal was actually reached once the LLM says it's done,
+//! from what it expects the page to look like afterward.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::node::{flatten, Node};
+use crate::redact::MASK;
+
+/// Something that should be true of an element once the user's goal has been reached. Only
+/// the parts that are given are checked.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Expectation {
+    /// The DOM ID of the element.
+    pub id: u32,
+    /// Whether or not the element should be on the page at all (e.g. `false` for a dialog
+    /// that should have closed).
+    pub present: Option<bool>,
+    /// What the element's value should be.
+    pub value: Option<String>,
+    /// Whether or not the element should be checked.
+    pub checked: Option<bool>,
+    /// Whether or not the element should be selected.
+    pub selected: Option<bool>,
+    /// Whether or not the element should be expanded.
+    pub expanded: Option<bool>,
+}
+
+/// Checks the given expectations against the given tree, returning descriptions of the ones
+/// that aren't met. Parts of values that have been redacted can't be checked, so they're
+/// assumed to be right.
+pub fn unmet_expectations(expectations: &[Expectation], tree: &[Node]) -> Vec<String> {
+    let nodes = flatten(tree);
+    let mut unmet = Vec::new();
+    for expectation in expectations {
+        let id = expectation.id;
+        let Some(node) = nodes.iter().find(|n| n.dom_id == id) else {
+            // Anything else about an element that isn't there can't be true either
+            if expectation.present != Some(false) {
+                unmet.push(format!("node {id} isn't on the page"));
+            }
+            continue;
+        };
+        if expectation.present == Some(false) {
+            unmet.push(format!("node {id} is still on the page"));
+        }
+
+        if let Some(expected) = &expectation.value {
+            let actual = node.value.as_deref().unwrap_or("");
+            if !values_match(actual, expected) {
+                unmet.push(format!(
+                    "node {id} has the value \"{actual}\", not \"{expected}\""
+                ));
+            }
+        }
+        for (property, expected) in [
+            ("checked", expectation.checked),
+            ("selected", expectation.selected),
+            ("expanded", expectation.expanded),
+        ] {
+            let Some(expected) = expected else {
+                continue;
+            };
+            let actual = node.properties.get(property).is_some_and(|v| v == "true");
+            if actual != expected {
+                unmet.push(format!(
+                    "node {id} is {}{property}",
+                    if actual { "" } else { "not " }
+                ));
+            }
+        }
+    }
+    unmet
+}
+
+/// Checks whether the given actual value of an element matches the expected one. Any parts of
+/// the actual value that have been redacted could have been anything, so they match anything.
+fn values_match(actual: &str, expected: &str) -> bool {
+    let (actual, expected) = (actual.trim(), expected.trim());
+    if actual == expected || !actual.contains(MASK) {
+        return actual == expected;
+    }
+    let parts: Vec<_> = actual.split(MASK).map(regex::escape).collect();
+    Regex::new(&format!("(?s)^{}$", parts.join(".*")))
+        .map(|re| re.is_match(expected))
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_values_must_match_exactly() {
+        assert!(values_match("Ann", "Ann"));
+        assert!(values_match(" Ann ", "Ann"));
+        assert!(!values_match("Ann", "Bob"));
+        assert!(!values_match("Ann", "Anne"));
+    }
+
+    #[test]
+    fn redacted_values_match_anything() {
+        assert!(values_match(MASK, "hunter2"));
+        assert!(values_match(MASK, ""));
+    }
+
+    #[test]
+    fn parts_around_redactions_must_still_match() {
+        let actual = format!("Email me at {MASK} tomorrow");
+        assert!(values_match(
+            &actual,
+            "Email me at ann@example.com tomorrow"
+        ));
+        assert!(!values_match(&actual, "Email me at ann@example.com today"));
+        assert!(!values_match(
+            &actual,
+            "Call me at ann@example.com tomorrow"
+        ));
+    }
+
+    #[test]
+    fn text_around_redactions_isnt_treated_as_a_pattern() {
+        let actual = format!("a.b {MASK}");
+        assert!(values_match(&actual, "a.b secret"));
+        assert!(!values_match(&actual, "axb secret"));
+    }
+
+    #[test]
+    fn several_redactions_each_match_anything() {
+        let actual = format!("{MASK} and {MASK}");
+        assert!(values_match(&actual, "one and two"));
+        assert!(!values_match(&actual, "one or two"));
+    }
+
+    #[test]
+    fn reports_unmet_expectations() {
+        let tree = vec![
+            Node::test(1, "checkbox", "Agree")
+                .with_value("Ann")
+                .with_property("checked", "true"),
+            Node::test(2, "dialog", "Cookies"),
+        ];
+        let expectations = [
+            Expectation {
+                id: 1,
+                value: Some("Bob".to_string()),
+                checked: Some(false),
+                ..Default::default()
+            },
+            Expectation {
+                id: 2,
+                present: Some(false),
+                ..Default::default()
+            },
+            Expectation {
+                id: 3,
+                value: Some("x".to_string()),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            unmet_expectations(&expectations, &tree),
+            [
+                "node 1 has the value \"Ann\", not \"Bob\"",
+                "node 1 is checked",
+                "node 2 is still on the page",
+                "node 3 isn't on the page",
+            ]
+        );
+    }
+
+    #[test]
+    fn met_expectations_arent_reported() {
+        let tree = vec![Node::test(1, "combobox", "Card")
+            .with_value(&format!("card {MASK}"))
+            .with_property("expanded", "true")];
+        let expectations = [
+            Expectation {
+                id: 1,
+                present: Some(true),
+                value: Some("card 4111 1111 1111 1111".to_string()),
+                expanded: Some(true),
+                selected: Some(false),
+                ..Default::default()
+            },
+            Expectation {
+                id: 2,
+                present: Some(false),
+                ..Default::default()
+            },
+        ];
+        assert!(unmet_expectations(&expectations, &tree).is_empty());
+    }
+}